@@ -0,0 +1,559 @@
+//! Queue which hands every item to every subscriber.
+//!
+//! Items pushed onto a [`Broadcast`] are kept until every [`Subscriber`] has
+//! received them, or until they are dropped for subscribers which have
+//! fallen too far behind, as decided by the [`SlowSubscriber`] policy.  Each
+//! item keeps track of how many subscribers have yet to receive it, so it
+//! can be dropped as soon as the last one has.
+//!
+//! Items are shared between subscribers behind an `Arc`, and are cloned
+//! after the internal lock has been released.  The last subscriber to
+//! receive an item gets the original rather than a clone.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+
+use crate::waitlist::WaitList;
+use crate::{cond_wait, lock, PushError, RecvError, TryRecvError};
+
+/// What to do when a subscriber falls so far behind that the broadcast queue
+/// is full of items it has not received yet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowSubscriber {
+  /// Make producers wait until the slowest subscriber has caught up.
+  Block,
+
+  /// Drop the oldest items for the subscriber, which silently skips them.
+  DropOldest,
+
+  /// Drop the oldest items for the subscriber, and report how many it missed
+  /// using [`RecvError::Lagged`].
+  Lag
+}
+
+/// An item along with the number of subscribers which have yet to receive
+/// it.
+struct Slot<I> {
+  item: Arc<I>,
+  pending: usize
+}
+
+struct State<I> {
+  slots: VecDeque<Slot<I>>,
+
+  /// Sequence number of the item at the front of `slots`.
+  head: u64,
+
+  /// Number of live subscribers.
+  subscribers: usize,
+
+  /// Async subscribers waiting for an item.
+  wakers: WaitList,
+
+  /// Async producers waiting for room.
+  push_wakers: WaitList,
+
+  closed: bool
+}
+
+impl<I> State<I> {
+  /// Sequence number the next item will get.
+  fn tail(&self) -> u64 {
+    self.head + self.slots.len() as u64
+  }
+
+  /// Index into `slots` of the item with sequence number `pos`, which must
+  /// not have been dropped.
+  fn index(&self, pos: u64) -> usize {
+    (pos - self.head) as usize
+  }
+
+  /// Drop items off the front which every subscriber has received.  The
+  /// items are returned, so they can be dropped once the lock has been
+  /// released.
+  fn trim(&mut self) -> Vec<Slot<I>> {
+    let mut done = Vec::new();
+    while self.slots.front().is_some_and(|slot| slot.pending == 0) {
+      done.extend(self.slots.pop_front());
+      self.head += 1;
+    }
+    done
+  }
+}
+
+/// Outcome of a subscriber looking for its next item.
+enum Next<I> {
+  Item(Arc<I>),
+  Lagged(u64),
+  Empty,
+  Closed
+}
+
+/// Outcome of a subscriber looking for its next item, with the lock released
+/// unless it has to wait.
+enum Step<'a, I> {
+  Done(Result<I, RecvError>),
+  Wait(MutexGuard<'a, State<I>>)
+}
+
+struct Shared<I> {
+  state: Mutex<State<I>>,
+
+  /// Signalled when an item has been pushed, or the queue has been closed.
+  signal: Condvar,
+
+  /// Signalled when room has been made, or the queue has been closed.
+  space: Condvar,
+
+  cap: usize,
+  policy: SlowSubscriber
+}
+
+impl<I> Shared<I> {
+  /// Returns `true` if a producer has to wait for room.
+  fn is_full(&self, state: &State<I>) -> bool {
+    self.policy == SlowSubscriber::Block && state.slots.len() >= self.cap
+  }
+
+  /// Add an item, for which there must be room, and wake up all
+  /// subscribers.
+  fn publish(&self, mut state: MutexGuard<'_, State<I>>, item: I) {
+    if state.subscribers == 0 {
+      drop(state);
+      drop(item);
+      return;
+    }
+    let evicted = if state.slots.len() >= self.cap {
+      state.head += 1;
+      state.slots.pop_front()
+    } else {
+      None
+    };
+    let pending = state.subscribers;
+    state.slots.push_back(Slot {
+      item: Arc::new(item),
+      pending
+    });
+    let wakers = state.wakers.take_all();
+    drop(state);
+
+    self.signal.notify_all();
+    for waker in wakers {
+      waker.wake();
+    }
+    drop(evicted);
+  }
+
+  /// Release the lock after `done` items have been dropped off the front,
+  /// waking up producers if that made room.
+  fn release(&self, mut state: MutexGuard<'_, State<I>>, done: Vec<Slot<I>>) {
+    if done.is_empty() || self.policy != SlowSubscriber::Block {
+      drop(state);
+      return;
+    }
+    let wakers = state.push_wakers.take_all();
+    drop(state);
+
+    self.space.notify_all();
+    for waker in wakers {
+      waker.wake();
+    }
+    drop(done);
+  }
+
+  fn close(&self) {
+    let mut state = lock(&self.state);
+    if state.closed {
+      return;
+    }
+    state.closed = true;
+    let wakers = state.wakers.take_all();
+    let push_wakers = state.push_wakers.take_all();
+    drop(state);
+
+    self.signal.notify_all();
+    self.space.notify_all();
+    for waker in wakers.into_iter().chain(push_wakers) {
+      waker.wake();
+    }
+  }
+
+  /// Move the subscriber at `pos` past its next item, if any, and return it.
+  fn advance(&self, pos: &mut u64, state: &mut State<I>) -> Next<I> {
+    if *pos < state.head {
+      let missed = state.head - *pos;
+      *pos = state.head;
+      if self.policy == SlowSubscriber::Lag {
+        return Next::Lagged(missed);
+      }
+    }
+    if *pos == state.tail() {
+      return if state.closed {
+        Next::Closed
+      } else {
+        Next::Empty
+      };
+    }
+    let idx = state.index(*pos);
+    *pos += 1;
+    let slot = &mut state.slots[idx];
+    slot.pending -= 1;
+    Next::Item(Arc::clone(&slot.item))
+  }
+}
+
+impl<I: Clone> Shared<I> {
+  /// Look for the next item of the subscriber at `pos`.  The lock is
+  /// released unless the subscriber has to wait.
+  fn next<'a>(
+    &self,
+    pos: &mut u64,
+    mut state: MutexGuard<'a, State<I>>
+  ) -> Step<'a, I> {
+    let item = match self.advance(pos, &mut state) {
+      Next::Item(item) => item,
+      Next::Lagged(n) => return Step::Done(Err(RecvError::Lagged(n))),
+      Next::Closed => return Step::Done(Err(RecvError::Closed)),
+      Next::Empty => return Step::Wait(state)
+    };
+    let done = state.trim();
+    self.release(state, done);
+    // The last subscriber to receive an item gets the original.
+    let item = Arc::try_unwrap(item).unwrap_or_else(|item| (*item).clone());
+    Step::Done(Ok(item))
+  }
+}
+
+
+/// Producer side of a queue which hands every item to every subscriber.
+///
+/// ```
+/// use sigq::{Broadcast, SlowSubscriber};
+/// let bc = Broadcast::new(16, SlowSubscriber::Block);
+/// let mut sub1 = bc.subscribe();
+/// let mut sub2 = bc.subscribe();
+/// bc.push("reload").unwrap();
+/// drop(bc);
+/// assert_eq!(sub1.recv(), Ok("reload"));
+/// assert_eq!(sub2.recv(), Ok("reload"));
+/// assert!(sub2.recv().is_err());
+/// ```
+pub struct Broadcast<I> {
+  shared: Arc<Shared<I>>
+}
+
+impl<I> Broadcast<I> {
+  /// Create a broadcast queue which holds at most `cap` items that have not
+  /// been received by every subscriber, and deals with subscribers that fall
+  /// further behind according to `policy`.
+  ///
+  /// # Panics
+  /// Panics if `cap` is zero.
+  pub fn new(cap: usize, policy: SlowSubscriber) -> Self {
+    assert!(cap > 0, "bounded queue capacity must be non-zero");
+    Broadcast {
+      shared: Arc::new(Shared {
+        state: Mutex::new(State {
+          slots: VecDeque::new(),
+          head: 0,
+          subscribers: 0,
+          wakers: WaitList::new(),
+          push_wakers: WaitList::new(),
+          closed: false
+        }),
+        signal: Condvar::new(),
+        space: Condvar::new(),
+        cap,
+        policy
+      })
+    }
+  }
+
+  /// Create a new subscriber, which will receive all items pushed from now
+  /// on.
+  pub fn subscribe(&self) -> Subscriber<I> {
+    let mut state = lock(&self.shared.state);
+    state.subscribers += 1;
+    let pos = state.tail();
+    drop(state);
+    Subscriber {
+      shared: Arc::clone(&self.shared),
+      pos
+    }
+  }
+
+  /// Push an item for all current subscribers to receive.  If there are no
+  /// subscribers the item is dropped.
+  ///
+  /// With the [`SlowSubscriber::Block`] policy this blocks while the queue is
+  /// full, until the slowest subscriber has made room.
+  ///
+  /// If the queue has been closed the item is returned in a
+  /// [`PushError::Closed`].
+  pub fn push(&self, item: I) -> Result<(), PushError<I>> {
+    let mut state = lock(&self.shared.state);
+    loop {
+      if state.closed {
+        return Err(PushError::Closed(item));
+      }
+      if !self.shared.is_full(&state) {
+        break;
+      }
+      state = cond_wait(&self.shared.space, state);
+    }
+    self.shared.publish(state, item);
+    Ok(())
+  }
+
+  /// Push an item without waiting for room.
+  ///
+  /// Returns the item in a [`PushError::Full`] if the queue is full under the
+  /// [`SlowSubscriber::Block`] policy, and in a [`PushError::Closed`] if the
+  /// queue has been closed.
+  pub fn try_push(&self, item: I) -> Result<(), PushError<I>> {
+    let state = lock(&self.shared.state);
+    if state.closed {
+      return Err(PushError::Closed(item));
+    }
+    if self.shared.is_full(&state) {
+      return Err(PushError::Full(item));
+    }
+    self.shared.publish(state, item);
+    Ok(())
+  }
+
+  /// Return a `Future` that pushes an item, waiting for room under the
+  /// [`SlowSubscriber::Block`] policy.
+  ///
+  /// The future is cancel-safe: if it is dropped before it completes, the
+  /// item is dropped along with it and has not been pushed.
+  pub fn apush(&self, item: I) -> BroadcastPushFuture<I> {
+    BroadcastPushFuture {
+      shared: Arc::clone(&self.shared),
+      item: Some(item),
+      id: None
+    }
+  }
+
+  /// Returns the maximum number of items the queue holds.
+  pub fn capacity(&self) -> usize {
+    self.shared.cap
+  }
+
+  /// Returns the policy for subscribers which fall behind.
+  pub fn policy(&self) -> SlowSubscriber {
+    self.shared.policy
+  }
+
+  /// Returns the number of live subscribers.
+  pub fn subscriber_count(&self) -> usize {
+    lock(&self.shared.state).subscribers
+  }
+
+  /// Close the queue.
+  ///
+  /// New items can no longer be pushed.  Subscribers receive the items which
+  /// remain on the queue, after which they get [`RecvError::Closed`].  The
+  /// queue is closed automatically when the `Broadcast` is dropped.
+  pub fn close(&self) {
+    self.shared.close();
+  }
+
+  /// Returns a boolean indicating whether the queue has been closed.
+  pub fn is_closed(&self) -> bool {
+    lock(&self.shared.state).closed
+  }
+}
+
+impl<I> Drop for Broadcast<I> {
+  fn drop(&mut self) {
+    self.shared.close();
+  }
+}
+
+
+/// Receiving side of a [`Broadcast`] queue, with its own position in the
+/// stream of items.
+///
+/// Cloning a subscriber creates a new subscriber at the same position.
+pub struct Subscriber<I> {
+  shared: Arc<Shared<I>>,
+
+  /// Sequence number of the next item to receive.
+  pos: u64
+}
+
+impl<I: Clone> Subscriber<I> {
+  /// Receive the next item.  If there is none, then block and wait for one
+  /// to be pushed.
+  pub fn recv(&mut self) -> Result<I, RecvError> {
+    let shared = &*self.shared;
+    let mut state = lock(&shared.state);
+    loop {
+      match shared.next(&mut self.pos, state) {
+        Step::Done(res) => return res,
+        Step::Wait(s) => state = cond_wait(&shared.signal, s)
+      }
+    }
+  }
+
+  /// Receive the next item, without waiting for one to be pushed.
+  pub fn try_recv(&mut self) -> Result<I, TryRecvError> {
+    let shared = &*self.shared;
+    match shared.next(&mut self.pos, lock(&shared.state)) {
+      Step::Done(Ok(item)) => Ok(item),
+      Step::Done(Err(RecvError::Lagged(n))) => Err(TryRecvError::Lagged(n)),
+      Step::Done(Err(RecvError::Closed)) => Err(TryRecvError::Closed),
+      Step::Wait(_) => Err(TryRecvError::Empty)
+    }
+  }
+
+  /// Return a `Future` which resolves to the next item.
+  ///
+  /// The future is cancel-safe: the subscriber only moves past an item when
+  /// the future resolves to it.
+  pub fn arecv(&mut self) -> RecvFuture<'_, I> {
+    RecvFuture {
+      sub: self,
+      id: None
+    }
+  }
+}
+
+impl<I> Subscriber<I> {
+  /// Returns the number of items the subscriber has yet to receive.
+  pub fn len(&self) -> usize {
+    let state = lock(&self.shared.state);
+    (state.tail() - self.pos.max(state.head)) as usize
+  }
+
+  /// Returns a boolean indicating whether the subscriber has received all
+  /// items pushed so far.
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Returns a boolean indicating whether the queue has been closed.
+  pub fn is_closed(&self) -> bool {
+    lock(&self.shared.state).closed
+  }
+}
+
+impl<I> Clone for Subscriber<I> {
+  fn clone(&self) -> Self {
+    let mut state = lock(&self.shared.state);
+    state.subscribers += 1;
+    let start = state.index(self.pos.max(state.head));
+    for slot in state.slots.range_mut(start..) {
+      slot.pending += 1;
+    }
+    drop(state);
+    Subscriber {
+      shared: Arc::clone(&self.shared),
+      pos: self.pos
+    }
+  }
+}
+
+impl<I> Drop for Subscriber<I> {
+  fn drop(&mut self) {
+    let mut state = lock(&self.shared.state);
+    state.subscribers -= 1;
+    let start = state.index(self.pos.max(state.head));
+    for slot in state.slots.range_mut(start..) {
+      slot.pending -= 1;
+    }
+    let done = state.trim();
+    self.shared.release(state, done);
+  }
+}
+
+
+#[doc(hidden)]
+pub struct RecvFuture<'a, I> {
+  sub: &'a mut Subscriber<I>,
+
+  /// Identifier on the queue's subscriber wait list.
+  id: Option<u64>
+}
+
+impl<I: Clone> Future for RecvFuture<'_, I> {
+  type Output = Result<I, RecvError>;
+  fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+    let this = self.get_mut();
+    let sub = &mut *this.sub;
+    let shared = &*sub.shared;
+    match shared.next(&mut sub.pos, lock(&shared.state)) {
+      Step::Done(res) => {
+        if this.id.is_some() {
+          lock(&shared.state).wakers.deregister(&mut this.id);
+        }
+        Poll::Ready(res)
+      }
+      Step::Wait(mut state) => {
+        state.wakers.register(&mut this.id, ctx.waker());
+        Poll::Pending
+      }
+    }
+  }
+}
+
+impl<I> Drop for RecvFuture<'_, I> {
+  fn drop(&mut self) {
+    if self.id.is_some() {
+      lock(&self.sub.shared.state).wakers.deregister(&mut self.id);
+    }
+  }
+}
+
+
+#[doc(hidden)]
+pub struct BroadcastPushFuture<I> {
+  shared: Arc<Shared<I>>,
+  item: Option<I>,
+
+  /// Identifier on the queue's producer wait list.
+  id: Option<u64>
+}
+
+// The item is never pinned.
+impl<I> Unpin for BroadcastPushFuture<I> {}
+
+impl<I> Future for BroadcastPushFuture<I> {
+  type Output = Result<(), PushError<I>>;
+  fn poll(
+    mut self: Pin<&mut Self>,
+    ctx: &mut Context<'_>
+  ) -> Poll<Self::Output> {
+    let this = &mut *self;
+    let item = this.item.take().expect("polled after completion");
+    let mut state = lock(&this.shared.state);
+    if state.closed {
+      state.push_wakers.deregister(&mut this.id);
+      return Poll::Ready(Err(PushError::Closed(item)));
+    }
+    if this.shared.is_full(&state) {
+      state.push_wakers.register(&mut this.id, ctx.waker());
+      this.item = Some(item);
+      return Poll::Pending;
+    }
+    state.push_wakers.deregister(&mut this.id);
+    this.shared.publish(state, item);
+    Poll::Ready(Ok(()))
+  }
+}
+
+impl<I> Drop for BroadcastPushFuture<I> {
+  fn drop(&mut self) {
+    if self.id.is_some() {
+      lock(&self.shared.state)
+        .push_wakers
+        .deregister(&mut self.id);
+    }
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,69 @@
+//! Queues with non-default settings.
+
+use crate::Queue;
+
+/// Builder for queues which need more than [`Queue::new()`] and
+/// [`Queue::bounded()`] offer.
+///
+/// ```
+/// use sigq::{Builder, Queue};
+/// let q: Queue<u32> = Builder::new().bounded(16).fair(true).build();
+/// assert_eq!(q.capacity(), Some(16));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Builder {
+  pub(crate) cap: Option<usize>,
+  pub(crate) fair: bool,
+  pub(crate) name: Option<String>
+}
+
+impl Builder {
+  /// Create a builder for an unbounded queue which does not enforce
+  /// fairness.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Limit the queue to hold at most `cap` nodes.
+  ///
+  /// # Panics
+  /// Panics if `cap` is zero.
+  pub fn bounded(mut self, cap: usize) -> Self {
+    assert!(cap > 0, "bounded queue capacity must be non-zero");
+    self.cap = Some(cap);
+    self
+  }
+
+  /// Serve consumers in the order they started waiting.
+  ///
+  /// By default a blocked thread is woken up by whichever means the operating
+  /// system sees fit, and a consumer that just arrived may take a node ahead
+  /// of one which has been waiting for a long time.  In fair mode blocked
+  /// threads and pending async consumers form a single line: a node is only
+  /// handed to a consumer once every consumer which started waiting before it
+  /// has been served.  This includes the non-blocking [`Queue::try_pop()`],
+  /// which reports an empty queue if all nodes are spoken for.
+  ///
+  /// Fairness comes at the cost of throughput under contention, since nodes
+  /// can not go to whichever consumer happens to be running.
+  pub fn fair(mut self, fair: bool) -> Self {
+    self.fair = fair;
+    self
+  }
+
+  /// Give the queue a name.
+  ///
+  /// With the `tracing` feature the name is attached to the queue's spans and
+  /// events, so traces show which queue a thread is waiting on.
+  pub fn name(mut self, name: impl Into<String>) -> Self {
+    self.name = Some(name.into());
+    self
+  }
+
+  /// Create the queue.
+  pub fn build<I>(self) -> Queue<I> {
+    Queue::from_builder(self)
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
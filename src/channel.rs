@@ -0,0 +1,408 @@
+//! Split producer/consumer handles for a queue.
+//!
+//! [`channel()`] and [`bounded_channel()`] return a [`Sender`] and a
+//! [`Receiver`] sharing a single queue.  Both handles can be cloned, and the
+//! queue keeps track of how many of each are alive:
+//!
+//! - When the last `Sender` is dropped the queue is closed, so receivers will
+//!   drain the remaining nodes and then get end-of-stream.
+//! - When the last `Receiver` is dropped the queue is closed, so pushing
+//!   returns the node in a [`PushError::Closed`].
+//!
+//! With the `futures-core` feature enabled [`Receiver`] implements
+//! `futures_core::Stream`, yielding nodes until the queue has been closed and
+//! drained.
+//!
+//! With the `futures-sink` feature enabled [`Sender`] implements
+//! `futures_sink::Sink`.  Each sender buffers at most one node; `poll_ready`
+//! only reports readiness once that node has made it onto the queue, so a
+//! bounded queue applies backpressure to the sink.  `poll_close` closes the
+//! queue, so `tx.close().await` flushes the sender and then closes the queue;
+//! [`Sender::close_channel()`] closes it right away.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(any(feature = "futures-core", feature = "futures-sink"))]
+use std::{
+  pin::Pin,
+  task::{Context, Poll}
+};
+
+#[cfg(feature = "futures-sink")]
+use crate::ClosedError;
+#[cfg(feature = "stats")]
+use crate::Stats;
+use crate::{
+  PopBatchFuture, PopFuture, PopTimeoutError, PopTimeoutFuture, PopWaiter,
+  PushError, PushFuture, Queue, TryPopError
+};
+
+/// Number of live handles of each kind.
+struct Counts {
+  senders: AtomicUsize,
+  receivers: AtomicUsize
+}
+
+/// Create an unbounded queue and return a sender and a receiver for it.
+///
+/// ```
+/// let (tx, rx) = sigq::channel();
+/// tx.push("hello").unwrap();
+/// drop(tx);
+/// assert_eq!(rx.pop(), Some("hello"));
+/// assert_eq!(rx.pop(), None);
+/// ```
+pub fn channel<I>() -> (Sender<I>, Receiver<I>) {
+  split(Queue::new())
+}
+
+/// Create a queue which can hold at most `cap` nodes and return a sender and
+/// a receiver for it.
+///
+/// # Panics
+/// Panics if `cap` is zero.
+pub fn bounded_channel<I>(cap: usize) -> (Sender<I>, Receiver<I>) {
+  split(Queue::bounded(cap))
+}
+
+fn split<I>(q: Queue<I>) -> (Sender<I>, Receiver<I>) {
+  let counts = Arc::new(Counts {
+    senders: AtomicUsize::new(1),
+    receivers: AtomicUsize::new(1)
+  });
+  let tx = Sender {
+    q: q.handle(),
+    counts: Arc::clone(&counts),
+    pending: None,
+    push_id: None
+  };
+  let rx = Receiver {
+    q,
+    counts,
+    waiter: PopWaiter::default()
+  };
+  (tx, rx)
+}
+
+
+/// Producer side of a queue created using [`channel()`] or
+/// [`bounded_channel()`].
+pub struct Sender<I> {
+  q: Queue<I>,
+  counts: Arc<Counts>,
+
+  /// Node handed to the sink which has not yet made it onto the queue.
+  #[cfg_attr(not(feature = "futures-sink"), allow(dead_code))]
+  pending: Option<I>,
+
+  /// Identifier on the queue's producer wait list while the sink waits for
+  /// space.
+  push_id: Option<u64>
+}
+
+impl<I> Sender<I> {
+  /// See [`Queue::push()`].
+  ///
+  /// Returns the node in a [`PushError::Closed`] if all receivers have been
+  /// dropped.
+  pub fn push(&self, item: I) -> Result<(), PushError<I>> {
+    self.q.push(item)
+  }
+
+  /// See [`Queue::push_at()`].
+  pub fn push_at(&self, item: I, due: Instant) -> Result<(), PushError<I>> {
+    self.q.push_at(item, due)
+  }
+
+  /// See [`Queue::push_after()`].
+  pub fn push_after(
+    &self,
+    item: I,
+    delay: Duration
+  ) -> Result<(), PushError<I>> {
+    self.q.push_after(item, delay)
+  }
+
+  /// See [`Queue::push_iter()`].
+  pub fn push_iter<T>(&self, iter: T) -> Result<(), PushError<VecDeque<I>>>
+  where
+    T: IntoIterator<Item = I>
+  {
+    self.q.push_iter(iter)
+  }
+
+  /// See [`Queue::try_push()`].
+  pub fn try_push(&self, item: I) -> Result<(), PushError<I>> {
+    self.q.try_push(item)
+  }
+
+  /// See [`Queue::apush()`].
+  pub fn apush(&self, item: I) -> PushFuture<I> {
+    self.q.apush(item)
+  }
+
+  /// See [`Queue::capacity()`].
+  pub fn capacity(&self) -> Option<usize> {
+    self.q.capacity()
+  }
+
+  /// See [`Queue::stats()`].
+  #[cfg(feature = "stats")]
+  pub fn stats(&self) -> Stats {
+    self.q.stats()
+  }
+
+  /// Close the channel's queue.  See [`Queue::close()`].
+  ///
+  /// This is not called `close()` so it does not shadow `SinkExt::close()`,
+  /// which flushes the sender's pending node before closing the queue.
+  pub fn close_channel(&self) {
+    self.q.close()
+  }
+
+  /// Returns a boolean indicating whether the queue has been closed, either
+  /// explicitly or because all receivers have been dropped.
+  pub fn is_closed(&self) -> bool {
+    self.q.is_closed()
+  }
+
+  /// Returns the number of receivers that are currently alive.
+  pub fn receiver_count(&self) -> usize {
+    self.counts.receivers.load(Ordering::Acquire)
+  }
+}
+
+impl<I> Clone for Sender<I> {
+  fn clone(&self) -> Self {
+    self.counts.senders.fetch_add(1, Ordering::Relaxed);
+    Sender {
+      q: self.q.handle(),
+      counts: Arc::clone(&self.counts),
+      pending: None,
+      push_id: None
+    }
+  }
+}
+
+/// Push all nodes from an iterator using [`Sender::push_iter()`].
+///
+/// Nodes which can not be pushed because the queue has been closed are
+/// dropped.
+impl<I> Extend<I> for Sender<I> {
+  fn extend<T: IntoIterator<Item = I>>(&mut self, iter: T) {
+    let _ = self.push_iter(iter);
+  }
+}
+
+// The pending node is never pinned.
+impl<I> Unpin for Sender<I> {}
+
+impl<I> Drop for Sender<I> {
+  fn drop(&mut self) {
+    self.q.abandon_push(&mut self.push_id);
+    if self.counts.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+      self.q.close();
+    }
+  }
+}
+
+#[cfg(feature = "futures-sink")]
+impl<I> Sender<I> {
+  /// Move the pending node, if any, onto the queue.
+  fn poll_flush_pending(
+    &mut self,
+    ctx: &mut Context<'_>
+  ) -> Poll<Result<(), ClosedError>> {
+    match self.pending.take() {
+      Some(item) => match self.q.poll_push(item, ctx, &mut self.push_id) {
+        Ok(()) => Poll::Ready(Ok(())),
+        Err(PushError::Full(item)) => {
+          self.pending = Some(item);
+          Poll::Pending
+        }
+        Err(PushError::Closed(_)) => Poll::Ready(Err(ClosedError))
+      },
+      None => Poll::Ready(Ok(()))
+    }
+  }
+}
+
+#[cfg(feature = "futures-sink")]
+impl<I> futures_sink::Sink<I> for Sender<I> {
+  type Error = ClosedError;
+
+  fn poll_ready(
+    self: Pin<&mut Self>,
+    ctx: &mut Context<'_>
+  ) -> Poll<Result<(), Self::Error>> {
+    let this = self.get_mut();
+    match this.poll_flush_pending(ctx) {
+      Poll::Ready(Ok(())) if this.q.is_closed() => {
+        Poll::Ready(Err(ClosedError))
+      }
+      res => res
+    }
+  }
+
+  fn start_send(self: Pin<&mut Self>, item: I) -> Result<(), Self::Error> {
+    let this = self.get_mut();
+    debug_assert!(this.pending.is_none(), "start_send() without poll_ready()");
+    this.pending = Some(item);
+    Ok(())
+  }
+
+  fn poll_flush(
+    self: Pin<&mut Self>,
+    ctx: &mut Context<'_>
+  ) -> Poll<Result<(), Self::Error>> {
+    self.get_mut().poll_flush_pending(ctx)
+  }
+
+  fn poll_close(
+    self: Pin<&mut Self>,
+    ctx: &mut Context<'_>
+  ) -> Poll<Result<(), Self::Error>> {
+    let this = self.get_mut();
+    match this.poll_flush_pending(ctx) {
+      Poll::Pending => Poll::Pending,
+      res => {
+        this.q.close();
+        res
+      }
+    }
+  }
+}
+
+
+/// Consumer side of a queue created using [`channel()`] or
+/// [`bounded_channel()`].
+pub struct Receiver<I> {
+  q: Queue<I>,
+  counts: Arc<Counts>,
+
+  /// Wait list entry and timer used while waiting as a stream.
+  waiter: PopWaiter
+}
+
+impl<I> Receiver<I> {
+  /// See [`Queue::pop()`].
+  ///
+  /// Returns `None` once all senders have been dropped and the queue has been
+  /// drained.
+  pub fn pop(&self) -> Option<I> {
+    self.q.pop()
+  }
+
+  /// See [`Queue::pop_timeout()`].
+  pub fn pop_timeout(&self, dur: Duration) -> Result<I, PopTimeoutError> {
+    self.q.pop_timeout(dur)
+  }
+
+  /// See [`Queue::pop_deadline()`].
+  pub fn pop_deadline(&self, deadline: Instant) -> Result<I, PopTimeoutError> {
+    self.q.pop_deadline(deadline)
+  }
+
+  /// See [`Queue::pop_batch()`].
+  pub fn pop_batch(&self, max: usize) -> Vec<I> {
+    self.q.pop_batch(max)
+  }
+
+  /// See [`Queue::try_pop()`].
+  pub fn try_pop(&self) -> Result<I, TryPopError> {
+    self.q.try_pop()
+  }
+
+  /// See [`Queue::try_drain_into()`].
+  pub fn try_drain_into(
+    &self,
+    buf: &mut Vec<I>,
+    max: usize
+  ) -> Result<usize, TryPopError> {
+    self.q.try_drain_into(buf, max)
+  }
+
+  /// See [`Queue::apop()`].
+  pub fn apop(&self) -> PopFuture<I> {
+    self.q.apop()
+  }
+
+  /// See [`Queue::apop_batch()`].
+  pub fn apop_batch(&self, max: usize) -> PopBatchFuture<I> {
+    self.q.apop_batch(max)
+  }
+
+  /// See [`Queue::apop_timeout()`].
+  pub fn apop_timeout(&self, dur: Duration) -> PopTimeoutFuture<I> {
+    self.q.apop_timeout(dur)
+  }
+
+  /// See [`Queue::apop_deadline()`].
+  pub fn apop_deadline(&self, deadline: Instant) -> PopTimeoutFuture<I> {
+    self.q.apop_deadline(deadline)
+  }
+
+  /// See [`Queue::was_empty()`].
+  pub fn was_empty(&self) -> bool {
+    self.q.was_empty()
+  }
+
+  /// See [`Queue::stats()`].
+  #[cfg(feature = "stats")]
+  pub fn stats(&self) -> Stats {
+    self.q.stats()
+  }
+
+  /// See [`Queue::close()`].
+  pub fn close(&self) {
+    self.q.close()
+  }
+
+  /// Returns a boolean indicating whether the queue has been closed, either
+  /// explicitly or because all senders have been dropped.
+  pub fn is_closed(&self) -> bool {
+    self.q.is_closed()
+  }
+
+  /// Returns the number of senders that are currently alive.
+  pub fn sender_count(&self) -> usize {
+    self.counts.senders.load(Ordering::Acquire)
+  }
+}
+
+impl<I> Clone for Receiver<I> {
+  fn clone(&self) -> Self {
+    self.counts.receivers.fetch_add(1, Ordering::Relaxed);
+    Receiver {
+      q: self.q.handle(),
+      counts: Arc::clone(&self.counts),
+      waiter: PopWaiter::default()
+    }
+  }
+}
+
+impl<I> Drop for Receiver<I> {
+  fn drop(&mut self) {
+    self.q.abandon_pop(&mut self.waiter);
+    if self.counts.receivers.fetch_sub(1, Ordering::AcqRel) == 1 {
+      self.q.close();
+    }
+  }
+}
+
+#[cfg(feature = "futures-core")]
+impl<I> futures_core::Stream for Receiver<I> {
+  type Item = I;
+  fn poll_next(
+    self: Pin<&mut Self>,
+    ctx: &mut Context<'_>
+  ) -> Poll<Option<Self::Item>> {
+    let this = self.get_mut();
+    this.q.poll_pop(ctx, &mut this.waiter, None)
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,746 @@
+//! Queue which survives process restarts by logging its nodes to disk.
+//!
+//! A [`DurableQueue`] keeps its nodes in a regular [`Queue`], and writes every
+//! push and every acknowledged pop to an append-only log:
+//!
+//! - Every record carries its length and a checksum.  A record which was only
+//!   partly written when the process went down is cut off the log when it is
+//!   opened again.
+//! - Popping a node hands out a [`Delivery`].  The node is only removed from
+//!   the log once the delivery has been acknowledged.  A delivery which is
+//!   dropped without having been acknowledged puts its node back onto the
+//!   queue, and nodes which were popped but not acknowledged when the process
+//!   went down are delivered again once the log has been reopened.
+//! - Once enough nodes have been acknowledged the log is compacted: the
+//!   records of the nodes which are still live are copied to a new log, which
+//!   then replaces the old one.  The same happens whenever a log is opened.
+//!   Only the position of each live record is kept in memory; the nodes
+//!   themselves are read back from the old log.
+
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::future::Future;
+use std::io::{self, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+  lock, DurablePushError, PopFuture, PopTimeoutError, Queue, TryPopError
+};
+
+/// Record of a pushed node.
+const PUSH: u8 = 0;
+
+/// Record of an acknowledged node.
+const ACK: u8 = 1;
+
+/// Size of a record's header; the length and checksum of its body.
+const HEADER: usize = 8;
+
+/// Size of the fixed part of a record's body; its kind and sequence number.
+const PREFIX: usize = 9;
+
+/// When the log is flushed to stable storage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsyncPolicy {
+  /// After every record.  A push or acknowledgement has made it to the disk
+  /// by the time it returns.
+  Always,
+
+  /// When a record is written and the log was last flushed at least the given
+  /// time ago.  Records written since then may be lost if the machine, rather
+  /// than just the process, goes down.
+  Interval(Duration),
+
+  /// Only when the log is compacted, or [`DurableQueue::sync()`] is called;
+  /// otherwise it is up to the operating system.
+  Never
+}
+
+/// Settings for opening a [`DurableQueue`].
+///
+/// ```
+/// use std::time::Duration;
+/// use sigq::{DurableOptions, FsyncPolicy};
+/// let opts = DurableOptions::new()
+///   .fsync(FsyncPolicy::Interval(Duration::from_millis(100)))
+///   .compact_after(10_000);
+/// ```
+#[derive(Clone, Debug)]
+pub struct DurableOptions {
+  fsync: FsyncPolicy,
+  compact_after: usize
+}
+
+impl Default for DurableOptions {
+  fn default() -> Self {
+    DurableOptions {
+      fsync: FsyncPolicy::Always,
+      compact_after: 1024
+    }
+  }
+}
+
+impl DurableOptions {
+  /// Create options which flush the log after every record, and compact it
+  /// after every 1024 acknowledged nodes.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Choose when the log is flushed to stable storage.
+  pub fn fsync(mut self, policy: FsyncPolicy) -> Self {
+    self.fsync = policy;
+    self
+  }
+
+  /// Compact the log once `count` nodes have been acknowledged since it was
+  /// last compacted.
+  ///
+  /// Compaction runs as part of the [`Delivery::ack()`] call which reaches
+  /// the threshold, and holds the log lock while it copies the live records.
+  /// That call, and any pushes and acknowledgements made in the meantime,
+  /// take longer the more nodes are live; a lower threshold spreads the work
+  /// out, a higher one does it less often.
+  ///
+  /// # Panics
+  /// Panics if `count` is zero.
+  pub fn compact_after(mut self, count: usize) -> Self {
+    assert!(count > 0, "compaction threshold must be non-zero");
+    self.compact_after = count;
+    self
+  }
+
+  /// Open the queue logged to `path`, creating the log if it doesn't exist.
+  ///
+  /// Nodes which were pushed onto the queue but never acknowledged are put
+  /// back onto it, in the order they were originally pushed.
+  ///
+  /// The log is locked until the queue, and every delivery and future popped
+  /// off it, have been dropped.  Opening a log which is already open, in this
+  /// process or another one, fails with [`io::ErrorKind::WouldBlock`].
+  pub fn open<I>(&self, path: impl AsRef<Path>) -> io::Result<DurableQueue<I>>
+  where
+    I: Serialize + DeserializeOwned
+  {
+    let (log, nodes) = Log::open(path.as_ref(), self)?;
+    let mut q = Queue::new();
+    q.extend(nodes);
+    Ok(DurableQueue {
+      q,
+      log: Arc::new(Mutex::new(log))
+    })
+  }
+}
+
+
+fn deserialize<I: DeserializeOwned>(payload: &[u8]) -> io::Result<I> {
+  bincode::deserialize(payload)
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// FNV-1a hash of a record's body.
+fn checksum(data: &[u8]) -> u32 {
+  data.iter().fold(0x811c_9dc5, |hash, byte| {
+    (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+  })
+}
+
+/// Append a record to `buf`.
+fn encode(
+  buf: &mut Vec<u8>,
+  kind: u8,
+  seq: u64,
+  payload: &[u8]
+) -> io::Result<()> {
+  let len = u32::try_from(PREFIX + payload.len()).map_err(|_| {
+    io::Error::new(io::ErrorKind::InvalidInput, "node too large to be logged")
+  })?;
+  let start = buf.len();
+  buf.extend_from_slice(&len.to_le_bytes());
+  buf.extend_from_slice(&[0; 4]);
+  buf.push(kind);
+  buf.extend_from_slice(&seq.to_le_bytes());
+  buf.extend_from_slice(payload);
+  let sum = checksum(&buf[start + HEADER..]);
+  buf[start + 4..start + HEADER].copy_from_slice(&sum.to_le_bytes());
+  Ok(())
+}
+
+/// Decode the record at `pos` in `data`.  Returns its kind, sequence number
+/// and payload, and the position of the next record; or `None` if the record
+/// is incomplete or damaged.
+fn decode(data: &[u8], pos: usize) -> Option<(u8, u64, &[u8], usize)> {
+  let header = data.get(pos..pos.checked_add(HEADER)?)?;
+  let len = u32::from_le_bytes(header[..4].try_into().ok()?) as usize;
+  let sum = u32::from_le_bytes(header[4..].try_into().ok()?);
+  let end = (pos + HEADER).checked_add(len)?;
+  let body = data.get(pos + HEADER..end)?;
+  if len < PREFIX || checksum(body) != sum {
+    return None;
+  }
+  let seq = u64::from_le_bytes(body[1..PREFIX].try_into().ok()?);
+  Some((body[0], seq, &body[PREFIX..], end))
+}
+
+/// Returns `path` with `suffix` appended to it.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+  let mut sibling = path.as_os_str().to_owned();
+  sibling.push(suffix);
+  PathBuf::from(sibling)
+}
+
+/// Lock the log at `path` until the returned file is dropped.  The lock is
+/// taken on a file next to the log, since the log itself is replaced every
+/// time it is compacted.
+fn lock_log(path: &Path) -> io::Result<File> {
+  let file = OpenOptions::new()
+    .write(true)
+    .create(true)
+    .truncate(false)
+    .open(sibling(path, ".lock"))?;
+  match file.try_lock() {
+    Ok(()) => Ok(file),
+    Err(TryLockError::WouldBlock) => Err(io::Error::new(
+      io::ErrorKind::WouldBlock,
+      "log is already open"
+    )),
+    Err(TryLockError::Error(e)) => Err(e)
+  }
+}
+
+/// A log which [`rewrite()`] has put in place.
+struct Rewritten {
+  /// The new log, opened for reading and appending.
+  file: File,
+
+  len: u64,
+
+  /// Whether the log's directory entry made it to stable storage.
+  synced: io::Result<()>
+}
+
+/// Copy the records of the live nodes from `src` to a new log which replaces
+/// the one at `path`.  Once the new log is in place the records in `live`
+/// are moved to where they are in it.
+///
+/// Returns an error only if the old log is still in place, in which case
+/// nothing has changed.  Once the new log has replaced it, the caller has to
+/// switch to it even if it could not be made sure to stay in place.
+fn rewrite<R: Read + Seek>(
+  path: &Path,
+  src: &mut R,
+  live: &mut BTreeMap<u64, Span>
+) -> io::Result<Rewritten> {
+  // The new log is opened before it is put in place, so that there is
+  // nothing left to fail once it has been.
+  let tmp = sibling(path, ".tmp");
+  let file = OpenOptions::new()
+    .read(true)
+    .append(true)
+    .create(true)
+    .open(&tmp)?;
+  let res =
+    copy_records(src, live, &file).and_then(|()| fs::rename(&tmp, path));
+  if let Err(e) = res {
+    let _ = fs::remove_file(&tmp);
+    return Err(e);
+  }
+
+  // The records were copied in the same order, so they now follow each
+  // other from the start of the log.
+  let mut len = 0;
+  for span in live.values_mut() {
+    span.pos = len;
+    len += span.len;
+  }
+  Ok(Rewritten {
+    file,
+    len,
+    synced: sync_dir(path)
+  })
+}
+
+/// Copy the records in `live` from `src` to `dest`, replacing anything which
+/// was there, and flush them to stable storage.
+fn copy_records<R: Read + Seek>(
+  src: &mut R,
+  live: &BTreeMap<u64, Span>,
+  dest: &File
+) -> io::Result<()> {
+  dest.set_len(0)?;
+  let mut out = BufWriter::new(dest);
+  let mut buf = Vec::new();
+  for span in live.values() {
+    buf.resize(span.len as usize, 0);
+    src.seek(SeekFrom::Start(span.pos))?;
+    src.read_exact(&mut buf)?;
+    out.write_all(&buf)?;
+  }
+  out.flush()?;
+  drop(out);
+  dest.sync_all()
+}
+
+/// Make sure a log which has been renamed into place stays there.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+  let dir = match path.parent() {
+    Some(dir) if !dir.as_os_str().is_empty() => dir,
+    _ => Path::new(".")
+  };
+  File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+  Ok(())
+}
+
+
+/// Where a record is in the log.
+#[derive(Clone, Copy)]
+struct Span {
+  pos: u64,
+  len: u64
+}
+
+
+struct Log {
+  path: PathBuf,
+
+  /// Keeps the log from being opened again while it is open.
+  _lock: File,
+
+  /// The log, opened for reading and appending.
+  file: File,
+
+  /// Length of the log up to the end of the last complete record.
+  len: u64,
+
+  fsync: FsyncPolicy,
+  last_sync: Instant,
+
+  /// Records of the nodes which have been pushed but not acknowledged yet, by
+  /// sequence number.
+  live: BTreeMap<u64, Span>,
+
+  /// Sequence number of the next node to be pushed.
+  next_seq: u64,
+
+  /// Number of nodes acknowledged since the log was last compacted.
+  acked: usize,
+
+  compact_after: usize
+}
+
+impl Log {
+  /// Replay the log at `path`, and replace it with a compacted copy.  Returns
+  /// the log along with the live nodes and their sequence numbers.
+  fn open<I: DeserializeOwned>(
+    path: &Path,
+    opts: &DurableOptions
+  ) -> io::Result<(Self, Vec<(u64, I)>)> {
+    let lock = lock_log(path)?;
+    let mut data = Vec::new();
+    match File::open(path) {
+      Ok(mut file) => {
+        file.read_to_end(&mut data)?;
+      }
+      Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+      Err(e) => return Err(e)
+    }
+
+    // Anything following a damaged record was written after it, by a
+    // process which did not get to finish writing the damaged one.
+    let mut live = BTreeMap::new();
+    let mut next_seq = 0;
+    let mut pos = 0;
+    while let Some((kind, seq, _, end)) = decode(&data, pos) {
+      match kind {
+        PUSH => {
+          let span = Span {
+            pos: pos as u64,
+            len: (end - pos) as u64
+          };
+          live.insert(seq, span);
+        }
+        ACK => {
+          live.remove(&seq);
+        }
+        _ => break
+      }
+      next_seq = next_seq.max(seq + 1);
+      pos = end;
+    }
+
+    let nodes = live
+      .iter()
+      .map(|(seq, span)| {
+        let start = span.pos as usize + HEADER + PREFIX;
+        let end = (span.pos + span.len) as usize;
+        Ok((*seq, deserialize(&data[start..end])?))
+      })
+      .collect::<io::Result<Vec<_>>>()?;
+
+    let new = rewrite(path, &mut Cursor::new(&data), &mut live)?;
+    new.synced?;
+    let log = Log {
+      path: path.to_path_buf(),
+      _lock: lock,
+      file: new.file,
+      len: new.len,
+      fsync: opts.fsync,
+      last_sync: Instant::now(),
+      live,
+      next_seq,
+      acked: 0,
+      compact_after: opts.compact_after
+    };
+    Ok((log, nodes))
+  }
+
+  /// Append a record to the log, and flush it according to the fsync
+  /// policy.  Returns where the record was written.
+  fn append(
+    &mut self,
+    kind: u8,
+    seq: u64,
+    payload: &[u8]
+  ) -> io::Result<Span> {
+    let mut buf = Vec::new();
+    encode(&mut buf, kind, seq, payload)?;
+    if let Err(e) = self.file.write_all(&buf) {
+      // Cut off whatever part of the record made it into the log, so that
+      // later records don't end up behind a damaged one.
+      let _ = self.file.set_len(self.len);
+      return Err(e);
+    }
+    let span = Span {
+      pos: self.len,
+      len: buf.len() as u64
+    };
+    self.len += span.len;
+    match self.fsync {
+      FsyncPolicy::Always => self.sync()?,
+      FsyncPolicy::Interval(dur) if self.last_sync.elapsed() >= dur => {
+        self.sync()?
+      }
+      _ => {}
+    }
+    Ok(span)
+  }
+
+  fn sync(&mut self) -> io::Result<()> {
+    self.file.sync_data()?;
+    self.last_sync = Instant::now();
+    Ok(())
+  }
+
+  /// Log a pushed node, and return its sequence number.
+  fn push(&mut self, payload: &[u8]) -> io::Result<u64> {
+    let seq = self.next_seq;
+    let span = self.append(PUSH, seq, payload)?;
+    self.next_seq += 1;
+    self.live.insert(seq, span);
+    Ok(seq)
+  }
+
+  /// Log an acknowledged node, and compact the log if it's time to.  The
+  /// caller holds the log lock, so compaction holds up other pushes and
+  /// acknowledgements until it has finished.
+  fn ack(&mut self, seq: u64) -> io::Result<()> {
+    self.append(ACK, seq, &[])?;
+    self.live.remove(&seq);
+    self.acked += 1;
+    if self.acked >= self.compact_after {
+      // Should compaction fail before the new log is in place, it is tried
+      // again on the next acknowledgement.
+      self.compact()?;
+    }
+    Ok(())
+  }
+
+  fn compact(&mut self) -> io::Result<()> {
+    let new = rewrite(&self.path, &mut self.file, &mut self.live)?;
+    self.file = new.file;
+    self.len = new.len;
+    self.acked = 0;
+    self.last_sync = Instant::now();
+    new.synced
+  }
+}
+
+
+/// Queue whose nodes are logged to disk, so they survive the process going
+/// down.
+///
+/// Nodes are popped in the form of a [`Delivery`], which needs to be
+/// acknowledged using [`Delivery::ack()`] once the node has been dealt with.
+/// Dropping a delivery without acknowledging it puts the node back onto the
+/// queue.
+///
+/// ```
+/// use sigq::DurableQueue;
+///
+/// let path = std::env::temp_dir()
+///   .join(format!("sigq-durable-doc-{}.log", std::process::id()));
+/// let q = DurableQueue::open(&path).unwrap();
+/// q.push(String::from("hello")).unwrap();
+/// q.push(String::from("world")).unwrap();
+/// let mut hello = q.pop().unwrap();
+/// assert_eq!(*hello, "hello");
+/// hello.ack().unwrap();
+/// let world = q.pop().unwrap();
+/// assert_eq!(*world, "world");
+/// drop(world);
+///
+/// // "world" was not acknowledged, so it was put back onto the queue.
+/// let world = q.pop().unwrap();
+/// assert_eq!(*world, "world");
+/// world.into_inner();
+/// drop((q, hello));
+///
+/// // Nor is it acknowledged in the log, so it is delivered again once the
+/// // log is reopened.
+/// let q: DurableQueue<String> = DurableQueue::open(&path).unwrap();
+/// assert_eq!(q.try_pop().map(|d| d.into_inner()), Ok(String::from("world")));
+/// # drop(q);
+/// # std::fs::remove_file(&path).unwrap();
+/// # std::fs::remove_file(path.with_extension("log.lock")).unwrap();
+/// ```
+pub struct DurableQueue<I> {
+  q: Queue<(u64, I)>,
+  log: Arc<Mutex<Log>>
+}
+
+impl<I> DurableQueue<I>
+where
+  I: Serialize + DeserializeOwned
+{
+  /// Open the queue logged to `path` using the default
+  /// [`DurableOptions`], creating the log if it doesn't exist.
+  pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+    DurableOptions::new().open(path)
+  }
+
+  /// Log a node and push it onto the queue, waking up one consumer, if any.
+  ///
+  /// If the queue has been closed the node is returned in a
+  /// [`DurablePushError::Closed`].  If it could not be serialized or logged
+  /// it is returned in a [`DurablePushError::Io`].
+  pub fn push(&self, item: I) -> Result<(), DurablePushError<I>> {
+    let payload = match bincode::serialize(&item) {
+      Ok(payload) => payload,
+      Err(e) => {
+        let e = io::Error::new(io::ErrorKind::InvalidInput, e);
+        return Err(DurablePushError::Io(item, e));
+      }
+    };
+
+    // Holding the log lock while pushing keeps the queue in the same order
+    // as the log, and keeps the queue from being closed in between.
+    let mut log = lock(&self.log);
+    if self.q.is_closed() {
+      return Err(DurablePushError::Closed(item));
+    }
+    let seq = match log.push(&payload) {
+      Ok(seq) => seq,
+      Err(e) => return Err(DurablePushError::Io(item, e))
+    };
+    self.q.push((seq, item)).map_err(|e| {
+      let (_, item) = e.into_inner();
+      DurablePushError::Closed(item)
+    })
+  }
+}
+
+impl<I> DurableQueue<I> {
+  fn deliver(&self, (seq, item): (u64, I)) -> Delivery<I> {
+    Delivery {
+      seq,
+      item: Some(item),
+      q: self.q.handle(),
+      log: Arc::clone(&self.log),
+      acked: false
+    }
+  }
+
+  /// Take the oldest node off the queue, blocking until one becomes
+  /// available.  See [`Queue::pop()`].
+  ///
+  /// Returns `None` if the queue is empty and has been closed.
+  pub fn pop(&self) -> Option<Delivery<I>> {
+    self.q.pop().map(|node| self.deliver(node))
+  }
+
+  /// Same as [`pop()`](#method.pop), but give up once `dur` has passed.
+  pub fn pop_timeout(
+    &self,
+    dur: Duration
+  ) -> Result<Delivery<I>, PopTimeoutError> {
+    self.q.pop_timeout(dur).map(|node| self.deliver(node))
+  }
+
+  /// Same as [`pop()`](#method.pop), but without blocking.
+  pub fn try_pop(&self) -> Result<Delivery<I>, TryPopError> {
+    self.q.try_pop().map(|node| self.deliver(node))
+  }
+
+  /// Return a `Future` which resolves to the oldest node on the queue, or
+  /// `None` if the queue is empty and has been closed.  See
+  /// [`Queue::apop()`].
+  pub fn apop(&self) -> DurablePopFuture<I> {
+    DurablePopFuture {
+      fut: self.q.apop(),
+      q: self.q.handle(),
+      log: Arc::clone(&self.log)
+    }
+  }
+
+  /// Returns the number of nodes on the queue.  Nodes which have been popped
+  /// but not acknowledged are not included.
+  pub fn len(&self) -> usize {
+    self.q.len()
+  }
+
+  /// Returns a boolean indicating whether the queue is empty.
+  pub fn is_empty(&self) -> bool {
+    self.q.is_empty()
+  }
+
+  /// Close the queue.  See [`Queue::close()`].
+  ///
+  /// Closing the queue does not affect the log; the nodes which are left on
+  /// the queue will be there once the log is opened again.
+  pub fn close(&self) {
+    let _log = lock(&self.log);
+    self.q.close();
+  }
+
+  /// Returns a boolean indicating whether the queue has been closed.
+  pub fn is_closed(&self) -> bool {
+    self.q.is_closed()
+  }
+
+  /// Flush the log to stable storage.
+  pub fn sync(&self) -> io::Result<()> {
+    lock(&self.log).sync()
+  }
+
+  /// Compact the log now, rather than waiting for enough nodes to be
+  /// acknowledged.
+  pub fn compact(&self) -> io::Result<()> {
+    lock(&self.log).compact()
+  }
+}
+
+
+/// A node popped off a [`DurableQueue`].
+///
+/// The node stays in the queue's log until the delivery has been
+/// acknowledged.  Dropping a delivery without acknowledging it pushes the
+/// node back onto the end of the queue, unless the queue has been closed; it
+/// is delivered again once the log is reopened either way.
+pub struct Delivery<I> {
+  seq: u64,
+
+  /// Only taken when the delivery is consumed or dropped.
+  item: Option<I>,
+
+  /// Queue to put the node back onto if it isn't acknowledged.
+  q: Queue<(u64, I)>,
+
+  log: Arc<Mutex<Log>>,
+  acked: bool
+}
+
+impl<I> Delivery<I> {
+  /// Acknowledge the node, removing it from the queue's log.
+  ///
+  /// Acknowledging a delivery more than once has no effect.  This is where
+  /// the log is compacted once enough nodes have been acknowledged; see
+  /// [`DurableOptions::compact_after()`].  Should compaction fail, its error
+  /// is returned, but the delivery has been acknowledged all the same.
+  pub fn ack(&mut self) -> io::Result<()> {
+    if !self.acked {
+      let mut log = lock(&self.log);
+      let res = log.ack(self.seq);
+      self.acked = !log.live.contains_key(&self.seq);
+      res?;
+    }
+    Ok(())
+  }
+
+  /// Returns a boolean indicating whether the delivery has been
+  /// acknowledged.
+  pub fn is_acked(&self) -> bool {
+    self.acked
+  }
+
+  /// Consume the delivery and return the node, whether it has been
+  /// acknowledged or not.
+  ///
+  /// A node which has not been acknowledged is not put back onto the queue,
+  /// but it stays in the log.
+  pub fn into_inner(mut self) -> I {
+    self.item.take().unwrap()
+  }
+}
+
+impl<I> Deref for Delivery<I> {
+  type Target = I;
+  fn deref(&self) -> &I {
+    self.item.as_ref().unwrap()
+  }
+}
+
+impl<I> DerefMut for Delivery<I> {
+  fn deref_mut(&mut self) -> &mut I {
+    self.item.as_mut().unwrap()
+  }
+}
+
+impl<I> Drop for Delivery<I> {
+  fn drop(&mut self) {
+    if let Some(item) = self.item.take() {
+      if !self.acked {
+        // Should the queue have been closed, the node is still in the log.
+        let _ = self.q.push((self.seq, item));
+      }
+    }
+  }
+}
+
+
+#[doc(hidden)]
+pub struct DurablePopFuture<I> {
+  fut: PopFuture<(u64, I)>,
+  q: Queue<(u64, I)>,
+  log: Arc<Mutex<Log>>
+}
+
+impl<I> Future for DurablePopFuture<I> {
+  type Output = Option<Delivery<I>>;
+  fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+    let this = self.get_mut();
+    Pin::new(&mut this.fut).poll(ctx).map(|node| {
+      node.map(|(seq, item)| Delivery {
+        seq,
+        item: Some(item),
+        q: this.q.handle(),
+        log: Arc::clone(&this.log),
+        acked: false
+      })
+    })
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
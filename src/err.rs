@@ -0,0 +1,209 @@
+//! Error types returned by queue operations.
+
+use std::fmt;
+
+/// Error returned when a node could not be pushed onto a queue.
+///
+/// The rejected node is handed back to the caller.
+pub enum PushError<I> {
+  /// The queue has been closed.
+  Closed(I),
+
+  /// The queue is bounded and full.
+  Full(I)
+}
+
+impl<I> PushError<I> {
+  /// Consume the error and return the node that could not be pushed.
+  pub fn into_inner(self) -> I {
+    match self {
+      PushError::Closed(item) | PushError::Full(item) => item
+    }
+  }
+}
+
+impl<I> fmt::Debug for PushError<I> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      PushError::Closed(_) => f.write_str("Closed(..)"),
+      PushError::Full(_) => f.write_str("Full(..)")
+    }
+  }
+}
+
+impl<I> fmt::Display for PushError<I> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      PushError::Closed(_) => f.write_str("queue is closed"),
+      PushError::Full(_) => f.write_str("queue is full")
+    }
+  }
+}
+
+impl<I> std::error::Error for PushError<I> {}
+
+
+/// Error returned when an operation fails because the queue has been closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClosedError;
+
+impl fmt::Display for ClosedError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("queue is closed")
+  }
+}
+
+impl std::error::Error for ClosedError {}
+
+
+/// Error returned by non-blocking pop operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryPopError {
+  /// The queue is empty, but nodes may still be pushed onto it.
+  Empty,
+
+  /// The queue is empty and has been closed.
+  Closed
+}
+
+impl fmt::Display for TryPopError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TryPopError::Empty => f.write_str("queue is empty"),
+      TryPopError::Closed => f.write_str("queue is empty and closed")
+    }
+  }
+}
+
+impl std::error::Error for TryPopError {}
+
+
+/// Error returned by pop operations that wait for a limited amount of time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PopTimeoutError {
+  /// No node became available before the time ran out.
+  Timeout,
+
+  /// The queue is empty and has been closed.
+  Closed
+}
+
+impl fmt::Display for PopTimeoutError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      PopTimeoutError::Timeout => f.write_str("timed out waiting for a node"),
+      PopTimeoutError::Closed => f.write_str("queue is empty and closed")
+    }
+  }
+}
+
+impl std::error::Error for PopTimeoutError {}
+
+
+/// Error returned by a [`Subscriber`](crate::Subscriber) waiting for an item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+  /// The subscriber fell behind, and this many items were dropped before it
+  /// got to them.  The next call continues with the oldest item still on the
+  /// broadcast queue.
+  Lagged(u64),
+
+  /// The subscriber has received all items, and the broadcast queue has been
+  /// closed.
+  Closed
+}
+
+impl fmt::Display for RecvError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RecvError::Lagged(n) => {
+        write!(f, "subscriber lagged behind by {} items", n)
+      }
+      RecvError::Closed => f.write_str("broadcast queue is closed")
+    }
+  }
+}
+
+impl std::error::Error for RecvError {}
+
+
+/// Error returned by [`Subscriber::try_recv()`](crate::Subscriber::try_recv).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+  /// There are no new items, but items may still be pushed.
+  Empty,
+
+  /// See [`RecvError::Lagged`].
+  Lagged(u64),
+
+  /// See [`RecvError::Closed`].
+  Closed
+}
+
+impl fmt::Display for TryRecvError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TryRecvError::Empty => f.write_str("no new items"),
+      TryRecvError::Lagged(n) => RecvError::Lagged(*n).fmt(f),
+      TryRecvError::Closed => RecvError::Closed.fmt(f)
+    }
+  }
+}
+
+impl std::error::Error for TryRecvError {}
+
+
+/// Error returned when a node could not be pushed onto a
+/// [`DurableQueue`](crate::DurableQueue).
+///
+/// The rejected node is handed back to the caller.
+#[cfg(feature = "durable")]
+pub enum DurablePushError<I> {
+  /// The queue has been closed.
+  Closed(I),
+
+  /// The node could not be serialized, or could not be written to the log.
+  Io(I, std::io::Error)
+}
+
+#[cfg(feature = "durable")]
+impl<I> DurablePushError<I> {
+  /// Consume the error and return the node that could not be pushed.
+  pub fn into_inner(self) -> I {
+    match self {
+      DurablePushError::Closed(item) | DurablePushError::Io(item, _) => item
+    }
+  }
+}
+
+#[cfg(feature = "durable")]
+impl<I> fmt::Debug for DurablePushError<I> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DurablePushError::Closed(_) => f.write_str("Closed(..)"),
+      DurablePushError::Io(_, e) => write!(f, "Io(.., {:?})", e)
+    }
+  }
+}
+
+#[cfg(feature = "durable")]
+impl<I> fmt::Display for DurablePushError<I> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DurablePushError::Closed(_) => f.write_str("queue is closed"),
+      DurablePushError::Io(_, e) => write!(f, "unable to log node: {}", e)
+    }
+  }
+}
+
+#[cfg(feature = "durable")]
+impl<I> std::error::Error for DurablePushError<I> {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      DurablePushError::Closed(_) => None,
+      DurablePushError::Io(_, e) => Some(e)
+    }
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
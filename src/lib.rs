@@ -1,130 +1,1828 @@
 //! Queue which supports pushing and poping nodes from threads/tasks, crossing
 //! sync/async boundaries.
+//!
+//! A queue can be closed using [`Queue::close()`].  Once closed no new nodes
+//! can be pushed onto it, but consumers will keep receiving the nodes that
+//! remain on the queue.  When a closed queue has been drained, the pop
+//! methods report end-of-stream rather than waiting for more nodes.
+//!
+//! Queues created using [`Queue::bounded()`] hold at most a fixed number of
+//! nodes.  Pushing onto a full bounded queue blocks (or, in an `async`
+//! context, waits) until a consumer has made room for the new node.
+//!
+//! Nodes pushed using [`Queue::push_at()`] or [`Queue::push_after()`] are
+//! held back until they are due.  Waiting consumers are woken up when the
+//! earliest such node becomes due.
+//!
+//! For producers and consumers living in different parts of a program,
+//! [`channel()`] splits a queue into cloneable [`Sender`] and [`Receiver`]
+//! handles which close the queue once either side has gone away.
+//!
+//! [`Builder`] creates queues with non-default settings, such as a fair mode
+//! which serves consumers in the order they started waiting.
+//!
+//! [`Select`] waits for a node on any of several queues, which may carry
+//! different node types.
+//!
+//! [`StealGroup`] holds a queue for each worker of a thread pool, and lets
+//! idle workers steal nodes from their siblings before they wait.
+//!
+//! [`PriorityQueue`] offers the same blocking and async pop semantics, but
+//! hands out nodes with higher priorities first.
+//!
+//! [`Broadcast`] hands every item to every [`Subscriber`] rather than to a
+//! single consumer.
+//!
+//! When a queue only ever has a single producer and a single consumer,
+//! [`spsc::channel()`] offers the same blocking and async semantics on top of
+//! a ring buffer which does not need a lock to push or pop nodes.
+//!
+//! # Features
+//! With the `lock-free` feature enabled, [`Queue`] keeps its nodes in a
+//! lock-free queue.  Producers and consumers which don't have to wait then
+//! only take the queue's internal lock when somebody needs to be woken up,
+//! which reduces contention when many threads share a busy queue.  Fair
+//! queues and queues holding delayed nodes still pop nodes under the lock.
+//!
+//! The lock-free queue can only be accessed at its ends, so
+//! [`Queue::peek_with()`], [`Queue::retain()`] and [`Queue::remove_first()`]
+//! take all nodes off it and push them back afterwards.  These calls are
+//! O(n) in the length of the queue even when only the first node is looked
+//! at, and producers and consumers wait for them to finish, so they are best
+//! kept off hot paths.
+//!
+//! The `stats` feature adds [`Queue::stats()`], which reports how many nodes
+//! have passed through a queue, how many consumers are waiting and how long
+//! they have waited.  The counters are updated using atomic operations.
+//!
+//! The `tracing` feature instruments queues using the `tracing` crate.
+//! Pushes, pops, consumers starting and stopping to wait and consumers being
+//! woken up are reported as events, and blocking calls and future polls run
+//! inside spans.  Each span and event carries the name given to the queue
+//! using [`Builder::name()`].
+//!
+//! The `durable` feature adds [`DurableQueue`], which logs its nodes to disk
+//! using `serde` and `bincode` so they survive the process going down.  Popped
+//! nodes stay in the log until they have been acknowledged, and the log is
+//! compacted every so often.  How often it is flushed to stable storage is
+//! chosen using [`FsyncPolicy`].
+//!
+//! # Lock poisoning
+//! The queues do not run nodes' code (such as `Drop` implementations) while
+//! holding their internal locks, and never leave their internal state
+//! half-updated, so a panic can not leave a queue in an inconsistent state.
+//! Should an internal lock be poisoned anyway, the queue recovers it and keeps
+//! going rather than propagating the panic to every other thread using the
+//! queue.  (The exception is the `Ord` implementation of a
+//! [`PriorityQueue`]'s priority type; if it panics the queue remains usable,
+//! but its ordering is unspecified.)
 
-use std::collections::VecDeque;
+mod broadcast;
+mod builder;
+mod channel;
+#[cfg(feature = "durable")]
+mod durable;
+mod err;
+mod nodes;
+mod priority;
+mod select;
+pub mod spsc;
+mod stats;
+mod steal;
+mod timer;
+mod trace;
+mod waitlist;
+
+use std::collections::{BTreeMap, VecDeque};
 use std::future::Future;
+use std::mem;
 use std::pin::Pin;
-use std::sync::{Arc, Condvar, Mutex};
-use std::task::{Context, Poll};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Wake, Waker};
 use std::thread;
+use std::time::{Duration, Instant};
+
+use nodes::{NoRoom, Nodes};
+use stats::{Counters, Pending};
+use timer::{Timer, TimerKey};
+use waitlist::WaitList;
+
+pub use broadcast::{
+  Broadcast, BroadcastPushFuture, RecvFuture, SlowSubscriber, Subscriber
+};
+pub use builder::Builder;
+pub use channel::{bounded_channel, channel, Receiver, Sender};
+#[cfg(feature = "durable")]
+pub use durable::{
+  Delivery, DurableOptions, DurablePopFuture, DurableQueue, FsyncPolicy
+};
+#[cfg(feature = "durable")]
+pub use err::DurablePushError;
+pub use err::{
+  ClosedError, PopTimeoutError, PushError, RecvError, TryPopError,
+  TryRecvError
+};
+pub use priority::{PriorityPopFuture, PriorityQueue};
+pub use select::{Select, SelectFuture, SelectTimeoutFuture};
+#[cfg(feature = "stats")]
+pub use stats::Stats;
+pub use steal::{StealFuture, StealGroup, Worker};
+
+/// Internal queue state protected by the queue mutex.
+struct Inner<I> {
+  /// Nodes waiting to be picked up by a consumer.
+  q: Nodes<I>,
+
+  /// Nodes which will be moved to `q` once they are due, ordered by due time
+  /// and then by insertion order.
+  delayed: BTreeMap<(Instant, u64), I>,
+
+  /// Sequence number assigned to the next delayed node.
+  delay_seq: u64,
+
+  /// Async consumers waiting for a node to become available.
+  wakers: WaitList,
+
+  /// Async producers waiting for space to become available.
+  push_wakers: WaitList,
+
+  /// Maximum number of nodes the queue may hold, if bounded.
+  cap: Option<usize>,
+
+  /// Number of threads blocked waiting for a node to become available.
+  blocked: usize,
+
+  /// Number of blocked threads which have been notified, but have not picked
+  /// up the queue lock yet.
+  notified: usize,
+
+  /// Number of threads blocked waiting for space to become available.
+  push_blocked: usize,
+
+  /// Serve consumers in the order they started waiting.  In this mode
+  /// blocked threads wait on `wakers` rather than the condition variable.
+  fair: bool,
+
+  /// Set once the queue has been closed.
+  closed: bool
+}
+
+impl<I> Inner<I> {
+  /// Total number of nodes on the queue, including delayed nodes which are
+  /// not yet due.
+  fn len(&self) -> usize {
+    self.q.total(self.delayed.len())
+  }
+
+  fn is_full(&self) -> bool {
+    match self.cap {
+      Some(cap) => self.len() >= cap,
+      None => false
+    }
+  }
+
+  /// Returns `true` if the queue has been closed and there are no nodes left
+  /// on it, due or not.
+  fn is_drained(&self) -> bool {
+    self.closed && self.len() == 0
+  }
+
+  /// Make sure there is room for `n` more nodes, and account for them.
+  fn reserve(&mut self, n: usize) -> Result<(), NoRoom> {
+    let delayed = self.delayed.len();
+    self.q.reserve(n, self.cap, self.closed, delayed)
+  }
+
+  /// Tell producers that don't take the queue lock how many consumers are
+  /// waiting for a node.  A consumer which is about to wait has to take
+  /// another look for nodes afterwards.
+  fn announce_consumers(&self) {
+    self.q.announce_consumers(self.blocked + self.wakers.len());
+  }
+
+  /// Tell consumers that don't take the queue lock how many producers are
+  /// waiting for room.  A producer which is about to wait has to take
+  /// another look for room afterwards.
+  fn announce_producers(&self) {
+    self
+      .q
+      .announce_producers(self.push_blocked + self.push_wakers.len());
+  }
+
+  /// Time at which the earliest delayed node becomes due.
+  fn next_due(&self) -> Option<Instant> {
+    self.delayed.keys().next().map(|(due, _)| *due)
+  }
+
+  /// Move delayed nodes which have become due to the end of the queue.
+  /// Returns the number of nodes moved.
+  fn promote(&mut self) -> usize {
+    if self.delayed.is_empty() {
+      return 0;
+    }
+    let now = Instant::now();
+    let mut count = 0;
+    while let Some(key) = self.delayed.keys().next().copied() {
+      if key.0 > now {
+        break;
+      }
+      if let Some(node) = self.delayed.remove(&key) {
+        self.q.push(node);
+        count += 1;
+      }
+    }
+    self.q.set_delayed(!self.delayed.is_empty());
+    count
+  }
+
+  /// Number of nodes the consumer identified by `id` on the wait list may
+  /// take.  In fair mode nodes are set aside for consumers which have been
+  /// waiting longer.
+  fn available(&self, id: Option<u64>) -> usize {
+    if self.fair {
+      self.q.len().saturating_sub(self.wakers.rank(id))
+    } else {
+      self.q.len()
+    }
+  }
+
+  /// Returns `true` if the consumer identified by `id` does not need to wait;
+  /// either because a node is available to it, or because the queue has been
+  /// closed and drained.
+  fn ready(&self, id: Option<u64>) -> bool {
+    self.available(id) > 0 || self.is_drained()
+  }
+
+  /// Take the oldest node off the queue, if the consumer identified by `id`
+  /// on the wait list may have it.
+  fn take_node(&mut self, id: Option<u64>) -> Option<I> {
+    if self.available(id) > 0 {
+      self.q.pop()
+    } else {
+      None
+    }
+  }
+
+  /// Move up to `max` of the oldest nodes into `buf`, as far as the consumer
+  /// identified by `id` on the wait list may have them.  Returns the number of
+  /// nodes moved.
+  fn drain_into(
+    &mut self,
+    buf: &mut Vec<I>,
+    max: usize,
+    id: Option<u64>
+  ) -> usize {
+    let count = max.min(self.available(id));
+    self.q.drain_into(buf, count)
+  }
+
+  /// Remove the nodes, due or not, for which `f` returns `false`.  The nodes
+  /// are returned so they can be dropped once the queue lock has been
+  /// released.
+  ///
+  /// `f` is called for every node before any of them is removed, so the
+  /// queue is left untouched should it panic.
+  fn remove_unless(&mut self, mut f: impl FnMut(&I) -> bool) -> Vec<I> {
+    let keys = self
+      .delayed
+      .iter()
+      .filter(|(_, node)| !f(node))
+      .map(|(key, _)| *key)
+      .collect::<Vec<_>>();
+    let delayed = self.delayed.len();
+    let mut removed = self.q.edit(delayed, |nodes| {
+      let keep = nodes.iter().map(&mut f).collect::<Vec<_>>();
+      let mut removed = Vec::new();
+      for (node, keep) in mem::take(nodes).into_iter().zip(keep) {
+        if keep {
+          nodes.push_back(node);
+        } else {
+          removed.push(node);
+        }
+      }
+      removed
+    });
+    let count = keys.len();
+    removed.extend(keys.iter().filter_map(|key| self.delayed.remove(key)));
+    self.q.unreserve(count);
+    self.q.set_delayed(!self.delayed.is_empty());
+    removed
+  }
+
+  /// Remove the first node for which `f` returns `true`.  Nodes which are
+  /// due are looked at first, in the order they would be popped, and then
+  /// delayed nodes in the order they become due.
+  fn remove_first(&mut self, mut f: impl FnMut(&I) -> bool) -> Option<I> {
+    let delayed = self.delayed.len();
+    let node = self.q.edit(delayed, |nodes| {
+      let pos = nodes.iter().position(&mut f)?;
+      nodes.remove(pos)
+    });
+    if node.is_some() {
+      return node;
+    }
+    let key = self
+      .delayed
+      .iter()
+      .find(|(_, node)| f(node))
+      .map(|(k, _)| *k)?;
+    let node = self.delayed.remove(&key);
+    self.q.unreserve(1);
+    self.q.set_delayed(!self.delayed.is_empty());
+    node
+  }
+
+  /// Take the wakers of async consumers to tell about `count` new nodes.
+  ///
+  /// In fair mode these are instead the wakers of all consumers, blocked
+  /// threads included, which have a node set aside for them but have not been
+  /// woken up yet.  Once a closed queue has been drained all consumers are
+  /// woken up, since those further back in line may have gone back to
+  /// waiting after the queue was closed.
+  fn consumer_wakers(&mut self, count: usize) -> Vec<Waker> {
+    if self.fair {
+      if self.is_drained() {
+        return self.wakers.take_all();
+      }
+      let n = self.q.len();
+      self.wakers.take_first(n)
+    } else {
+      self.wakers.take(count)
+    }
+  }
+
+  /// Pick the consumers to wake up for `count` nodes which were made
+  /// available, for no more than `count` wakeups in total.  Blocked threads
+  /// which haven't been notified yet go first, since they are sure to take a
+  /// look at the queue; the rest of the wakeups go to async consumers.
+  /// Returns the async consumers' wakers and the number of threads to notify.
+  fn consumers_to_wake(&mut self, count: usize) -> (Vec<Waker>, usize) {
+    let blocked = count.min(self.blocked.saturating_sub(self.notified));
+    self.notified += blocked;
+    (self.consumer_wakers(count - blocked), blocked)
+  }
+}
+
+/// Lock a mutex, recovering the guard if the mutex has been poisoned.
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+  m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Wait on a condition variable, recovering the guard if the mutex has been
+/// poisoned.
+fn cond_wait<'a, T>(
+  cv: &Condvar,
+  guard: MutexGuard<'a, T>
+) -> MutexGuard<'a, T> {
+  cv.wait(guard).unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Wait on a condition variable for at most `dur`, recovering the guard if
+/// the mutex has been poisoned.
+fn cond_wait_timeout<'a, T>(
+  cv: &Condvar,
+  guard: MutexGuard<'a, T>,
+  dur: Duration
+) -> MutexGuard<'a, T> {
+  match cv.wait_timeout(guard, dur) {
+    Ok((guard, _)) => guard,
+    Err(e) => e.into_inner().0
+  }
+}
+
+// Wakeup protocol
+//
+// Blocked threads wait on a condition variable, while async tasks register
+// their wakers on a wait list.  Every node made available wakes up one
+// consumer: a blocked thread which hasn't been notified yet if there is one,
+// since it is sure to take a look at the queue, and an async task otherwise.
+// An async task which is never polled again therefore can't keep a blocked
+// thread from getting a node.  (Producers waiting for space can not see each
+// other, so every slot made available wakes up one of each kind.)  This
+// keeps the following invariants:
+//
+// - A consumer only goes to sleep after having seen an empty queue while
+//   holding the queue lock, so no node can slip past it unannounced.
+// - A woken consumer always checks the queue before giving up, including when
+//   its timeout expires at the same time.
+// - An async consumer which is dropped after having been woken up passes the
+//   wakeup on to the next waiter if the node is still there.
+// - Every waiting consumer is set to wake up no later than when the earliest
+//   delayed node becomes due.
+//
+// In fair mode blocked threads are put on the wait list as well, and the
+// condition variable is left to producers.  A consumer may only take a node
+// if there are more nodes than consumers ahead of it in line, and it is the
+// consumers at the front of the line which are woken up.
+
+/// Wake up one blocked thread and one async task, if any, waiting on the same
+/// condition.
+fn wake_one(signal: &Condvar, waker: Option<Waker>) {
+  signal.notify_one();
+  if let Some(waker) = waker {
+    waker.wake();
+  }
+}
+
+/// Wake up to `count` blocked threads, and the given async tasks, waiting on
+/// the same condition.
+fn wake_many(signal: &Condvar, wakers: Vec<Waker>, count: usize) {
+  for _ in 0..count {
+    signal.notify_one();
+  }
+  for waker in wakers {
+    waker.wake();
+  }
+}
+
+/// Waker which unparks a blocked thread.
+struct Unparker(thread::Thread);
+
+impl Wake for Unparker {
+  fn wake(self: Arc<Self>) {
+    self.0.unpark();
+  }
+
+  fn wake_by_ref(self: &Arc<Self>) {
+    self.0.unpark();
+  }
+}
+
+/// Returns the earlier of two optional points in time.
+fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+  match (a, b) {
+    (Some(a), Some(b)) => Some(a.min(b)),
+    (a, None) => a,
+    (None, b) => b
+  }
+}
+
+/// A point in time so far off that it stands in for "never".
+fn far_future() -> Instant {
+  Instant::now() + Duration::from_secs(30 * 365 * 24 * 60 * 60)
+}
+
+/// State kept by an async consumer while it waits for a node.
+#[derive(Default)]
+pub(crate) struct PopWaiter {
+  /// Identifier on the queue's consumer wait list.
+  id: Option<u64>,
+
+  /// Timer scheduled to wake the consumer up when the earliest delayed node
+  /// becomes due or its deadline is reached.
+  timer_key: Option<TimerKey>,
+
+  /// Counts the consumer as pending while it waits.
+  pending: Option<Pending>
+}
 
 pub struct Queue<I> {
   signal: Arc<Condvar>,
-  q: Arc<Mutex<VecDeque<I>>>
+  space: Arc<Condvar>,
+  q: Arc<Mutex<Inner<I>>>,
+  timer: Arc<Timer>,
+  stats: Arc<Counters>,
+  name: Option<Arc<str>>,
+
+  /// Nodes which can be pushed and popped without the queue lock.
+  #[cfg(feature = "lock-free")]
+  nodes: Nodes<I>
 }
 
 impl<I> Queue<I> {
   /// Create, and return, a new queue.
   pub fn new() -> Self {
+    Builder::new().build()
+  }
+
+  /// Create, and return, a new queue which can hold at most `cap` nodes.
+  ///
+  /// # Panics
+  /// Panics if `cap` is zero.
+  pub fn bounded(cap: usize) -> Self {
+    Builder::new().bounded(cap).build()
+  }
+
+  fn from_builder(b: Builder) -> Self {
+    #[cfg(not(feature = "lock-free"))]
+    let q = Nodes::new();
+    #[cfg(feature = "lock-free")]
+    let nodes = Nodes::new(b.cap, b.fair);
+    #[cfg(feature = "lock-free")]
+    let q = nodes.handle();
+
     Queue {
       signal: Arc::new(Condvar::new()),
-      q: Arc::new(Mutex::new(VecDeque::new()))
+      space: Arc::new(Condvar::new()),
+      q: Arc::new(Mutex::new(Inner {
+        q,
+        delayed: BTreeMap::new(),
+        delay_seq: 0,
+        wakers: WaitList::new(),
+        push_wakers: WaitList::new(),
+        cap: b.cap,
+        blocked: 0,
+        notified: 0,
+        push_blocked: 0,
+        fair: b.fair,
+        closed: false
+      })),
+      timer: Arc::new(Timer::new()),
+      stats: Counters::new(),
+      name: b.name.map(Arc::from),
+      #[cfg(feature = "lock-free")]
+      nodes
     }
   }
 
+  /// Return a new handle to the same underlying queue.
+  pub(crate) fn handle(&self) -> Self {
+    Queue {
+      signal: Arc::clone(&self.signal),
+      space: Arc::clone(&self.space),
+      q: Arc::clone(&self.q),
+      timer: Arc::clone(&self.timer),
+      stats: Arc::clone(&self.stats),
+      name: self.name.clone(),
+      #[cfg(feature = "lock-free")]
+      nodes: self.nodes.handle()
+    }
+  }
+
+  /// Push a node without taking the queue lock, unless consumers need to be
+  /// woken up.  If the queue is full the node is handed back in a
+  /// [`PushError::Full`], and the caller needs to take the queue lock in
+  /// order to wait for room.
+  #[cfg(feature = "lock-free")]
+  fn push_unlocked(&self, item: I) -> Result<(), PushError<I>> {
+    match self.nodes.push_unlocked(item) {
+      Ok(false) => {
+        self.pushed(1, self.nodes.total(0));
+        Ok(())
+      }
+      Ok(true) => {
+        self.release_nodes(lock(&self.q), 1);
+        Ok(())
+      }
+      Err((NoRoom::Closed, item)) => Err(PushError::Closed(item)),
+      Err((NoRoom::Full, item)) => Err(PushError::Full(item))
+    }
+  }
+
+  /// Pop a node without taking the queue lock, unless producers need to be
+  /// woken up.  Returns `None` if no node is ready, or if nodes must be
+  /// popped while holding the queue lock.
+  #[cfg(feature = "lock-free")]
+  fn pop_unlocked(&self) -> Option<I> {
+    let (node, wake) = self.nodes.pop_unlocked()?;
+    self.popped(1);
+    if wake {
+      self.release(lock(&self.q), 1, 0);
+    }
+    Some(node)
+  }
+
+  /// Account for `count` nodes pushed onto the queue, after which it holds
+  /// `len` nodes.
+  fn pushed(&self, count: usize, len: usize) {
+    self.stats.pushed(count, len);
+    trace::pushed(self.name(), count, len);
+  }
+
+  /// Account for `count` nodes popped off the queue.
+  fn popped(&self, count: usize) {
+    self.stats.popped(count);
+    trace::popped(self.name(), count);
+  }
+
+  /// Account for a blocked thread until the returned guard is dropped.
+  fn block(&self) -> impl Sized + '_ {
+    (self.stats.block(), trace::block(self.name()))
+  }
+
+  /// Wake up `blocked` blocked threads and the async consumers behind
+  /// `wakers`.
+  fn wake_consumers(&self, wakers: Vec<Waker>, blocked: usize) {
+    trace::wake(self.name(), blocked, wakers.len());
+    wake_many(&self.signal, wakers, blocked);
+  }
+
+  /// Release the queue lock after `count` nodes have been removed from the
+  /// queue, while `promoted` delayed nodes were moved onto it.
+  ///
+  /// If the queue is bounded then as many producers waiting for space as
+  /// there were nodes removed are woken up.  Readers are woken up for any
+  /// promoted nodes left on the queue, since nobody has been told about them.
+  /// Once a closed queue has been drained all readers are woken up; with the
+  /// `lock-free` feature they may have started waiting while the last node
+  /// was being popped.
+  fn release(
+    &self,
+    mut inner: MutexGuard<'_, Inner<I>>,
+    count: usize,
+    promoted: usize
+  ) {
+    let extra = promoted.saturating_sub(count).min(inner.q.len());
+    let (wakers, blocked) = if inner.is_drained() {
+      inner.notified = inner.blocked;
+      (inner.wakers.take_all(), inner.blocked)
+    } else if extra > 0 || inner.fair {
+      inner.consumers_to_wake(extra)
+    } else {
+      (Vec::new(), 0)
+    };
+    let push_wakers = if inner.cap.is_some() {
+      inner.push_wakers.take(count)
+    } else {
+      Vec::new()
+    };
+    let bounded = inner.cap.is_some();
+    inner.announce_consumers();
+    drop(inner);
+
+    self.wake_consumers(wakers, blocked);
+    if bounded {
+      wake_many(&self.space, push_wakers, count);
+    }
+  }
+
+  /// Release the queue lock after `count` nodes have been pushed onto the
+  /// queue, and wake up as many consumers as there are new nodes.
+  fn release_nodes(&self, mut inner: MutexGuard<'_, Inner<I>>, count: usize) {
+    self.pushed(count, inner.len());
+    let (wakers, blocked) = inner.consumers_to_wake(count);
+    drop(inner);
+    self.wake_consumers(wakers, blocked);
+  }
+
+  /// Release the queue lock after a consumer has stopped waiting without
+  /// getting a node.
+  ///
+  /// If the consumer had been woken up for a node which is still on the
+  /// queue, the wakeup is passed on to another consumer so the node isn't
+  /// left sitting on the queue.  In fair mode any nodes which were set aside
+  /// for the consumer go to the consumers next in line.
+  fn stop_waiting(
+    &self,
+    mut inner: MutexGuard<'_, Inner<I>>,
+    id: &mut Option<u64>
+  ) {
+    let woken = inner.wakers.deregister(id);
+    inner.announce_consumers();
+    let (wakers, blocked) = if inner.fair {
+      inner.consumers_to_wake(0)
+    } else if woken && !inner.q.is_empty() {
+      inner.consumers_to_wake(1)
+    } else {
+      return;
+    };
+    drop(inner);
+    self.wake_consumers(wakers, blocked);
+  }
+
+  /// Block until signalled, or until the earliest delayed node becomes due or
+  /// `deadline` is reached, whichever comes first.
+  ///
+  /// In fair mode the thread is put on the wait list, where `id` identifies
+  /// it, rather than waiting on the condition variable.
+  fn wait<'a>(
+    &'a self,
+    mut inner: MutexGuard<'a, Inner<I>>,
+    deadline: Option<Instant>,
+    id: &mut Option<u64>
+  ) -> MutexGuard<'a, Inner<I>> {
+    let wake_at = earliest(inner.next_due(), deadline);
+    if inner.fair {
+      let waker = Waker::from(Arc::new(Unparker(thread::current())));
+      inner.wakers.register(id, &waker);
+      inner.announce_consumers();
+      if inner.ready(*id) {
+        return inner;
+      }
+      drop(inner);
+      let _blocked = self.block();
+      match wake_at {
+        Some(wake_at) => {
+          let dur = wake_at.saturating_duration_since(Instant::now());
+          thread::park_timeout(dur);
+        }
+        None => thread::park()
+      }
+      return lock(&self.q);
+    }
+
+    inner.blocked += 1;
+    inner.announce_consumers();
+    if !inner.ready(None) {
+      let _blocked = self.block();
+      inner = match wake_at {
+        Some(wake_at) => {
+          let dur = wake_at.saturating_duration_since(Instant::now());
+          cond_wait_timeout(&self.signal, inner, dur)
+        }
+        None => cond_wait(&self.signal, inner)
+      };
+
+      // The thread may have timed out rather than been notified, in which
+      // case producers only end up notifying one thread too many later on.
+      inner.notified = inner.notified.saturating_sub(1);
+    }
+    inner.blocked -= 1;
+    inner.announce_consumers();
+    inner
+  }
+
+  /// Block until a consumer has made room for a node, or the queue has been
+  /// closed.
+  fn wait_for_space<'a>(
+    &'a self,
+    mut inner: MutexGuard<'a, Inner<I>>
+  ) -> MutexGuard<'a, Inner<I>> {
+    inner.push_blocked += 1;
+    inner.announce_producers();
+    if inner.is_full() && !inner.closed {
+      inner = cond_wait(&self.space, inner);
+    }
+    inner.push_blocked -= 1;
+    inner.announce_producers();
+    inner
+  }
+
+  /// Register the task's waker so it is woken up when a node is pushed, and
+  /// also when the earliest delayed node becomes due or `deadline` is
+  /// reached, whichever comes first.
+  fn register_pop_waker(
+    &self,
+    inner: &mut Inner<I>,
+    deadline: Option<Instant>,
+    waiter: &mut PopWaiter,
+    ctx: &mut Context<'_>
+  ) {
+    match earliest(inner.next_due(), deadline) {
+      Some(wake_at) => {
+        let key = self.timer.schedule(waiter.timer_key, wake_at, ctx.waker());
+        waiter.timer_key = Some(key);
+      }
+      None => self.cancel_timer(&mut waiter.timer_key)
+    }
+    inner.wakers.register(&mut waiter.id, ctx.waker());
+    inner.announce_consumers();
+    if waiter.pending.is_none() {
+      trace::pending(self.name());
+      waiter.pending = Some(self.stats.pend());
+    } else {
+      trace::repoll(self.name());
+    }
+    if inner.ready(waiter.id) {
+      ctx.waker().wake_by_ref();
+    }
+  }
+
+  /// Take a consumer which is done waiting, because it has got a node or the
+  /// queue has been drained, off the wait list.
+  fn finish_pop(&self, inner: &mut Inner<I>, waiter: &mut PopWaiter) {
+    inner.wakers.deregister(&mut waiter.id);
+    self.done_waiting(waiter);
+  }
+
+  /// Take a consumer which is going away before it is done off the wait
+  /// list.  See [`stop_waiting()`](#method.stop_waiting).
+  pub(crate) fn abandon_pop(&self, waiter: &mut PopWaiter) {
+    self.done_waiting(waiter);
+    if waiter.id.is_none() {
+      return;
+    }
+    self.stop_waiting(lock(&self.q), &mut waiter.id);
+  }
+
+  /// Take a producer which is going away before it has pushed its node off
+  /// the wait list.
+  ///
+  /// If the producer had been woken up for space which is still available,
+  /// the wakeup is passed on to another producer.
+  pub(crate) fn abandon_push(&self, id: &mut Option<u64>) {
+    if id.is_none() {
+      return;
+    }
+    let mut inner = lock(&self.q);
+    let woken = inner.push_wakers.deregister(id);
+    inner.announce_producers();
+    if woken && !inner.closed && !inner.is_full() {
+      let waker = inner.push_wakers.take_one();
+      drop(inner);
+      wake_one(&self.space, waker);
+    }
+  }
+
+  /// Stop a consumer's timer, and stop counting it as pending.
+  fn done_waiting(&self, waiter: &mut PopWaiter) {
+    self.cancel_timer(&mut waiter.timer_key);
+    if waiter.pending.take().is_some() {
+      trace::done_waiting(self.name());
+    }
+  }
+
+  fn cancel_timer(&self, timer_key: &mut Option<TimerKey>) {
+    if let Some(key) = timer_key.take() {
+      self.timer.cancel(key);
+    }
+  }
+
+  /// Poll for the oldest node on the queue, registering the task's waker if
+  /// none is available.  If a `deadline` is given, the task is also woken up
+  /// once it has been reached; it is up to the caller to give up.
+  pub(crate) fn poll_pop(
+    &self,
+    ctx: &mut Context<'_>,
+    waiter: &mut PopWaiter,
+    deadline: Option<Instant>
+  ) -> Poll<Option<I>> {
+    let _span = trace::span(self.name(), "apop");
+    #[cfg(feature = "lock-free")]
+    if waiter.id.is_none() {
+      if let Some(node) = self.pop_unlocked() {
+        return Poll::Ready(Some(node));
+      }
+    }
+
+    let mut inner = lock(&self.q);
+    let promoted = inner.promote();
+    match inner.take_node(waiter.id) {
+      Some(node) => {
+        self.finish_pop(&mut inner, waiter);
+        self.popped(1);
+        self.release(inner, 1, promoted);
+        Poll::Ready(Some(node))
+      }
+      None if inner.is_drained() => {
+        self.stop_waiting(inner, &mut waiter.id);
+        self.done_waiting(waiter);
+        Poll::Ready(None)
+      }
+      None => {
+        self.register_pop_waker(&mut inner, deadline, waiter, ctx);
+        Poll::Pending
+      }
+    }
+  }
+
+  /// Poll for up to `max` of the oldest nodes on the queue, registering the
+  /// task's waker if none are available.
+  fn poll_pop_batch(
+    &self,
+    max: usize,
+    ctx: &mut Context<'_>,
+    waiter: &mut PopWaiter
+  ) -> Poll<Vec<I>> {
+    let _span = trace::span(self.name(), "apop_batch");
+    let mut inner = lock(&self.q);
+    let promoted = inner.promote();
+    let mut nodes = Vec::new();
+    let count = inner.drain_into(&mut nodes, max, waiter.id);
+    if count == 0 {
+      if !inner.is_drained() {
+        self.register_pop_waker(&mut inner, None, waiter, ctx);
+        return Poll::Pending;
+      }
+      self.stop_waiting(inner, &mut waiter.id);
+      self.done_waiting(waiter);
+      return Poll::Ready(nodes);
+    }
+    self.finish_pop(&mut inner, waiter);
+    self.popped(count);
+    self.release(inner, count, promoted);
+    Poll::Ready(nodes)
+  }
+
+  /// Attempt to push a node, registering the task's waker if the queue is
+  /// full.  In that case the node is handed back in a [`PushError::Full`].
+  ///
+  /// `id` identifies the producer on the wait list.
+  pub(crate) fn poll_push(
+    &self,
+    item: I,
+    ctx: &mut Context<'_>,
+    id: &mut Option<u64>
+  ) -> Result<(), PushError<I>> {
+    let _span = trace::span(self.name(), "apush");
+    #[cfg(feature = "lock-free")]
+    let item = match id {
+      None => match self.push_unlocked(item) {
+        Err(PushError::Full(item)) => item,
+        res => return res
+      },
+      Some(_) => item
+    };
+
+    let mut inner = lock(&self.q);
+    match inner.reserve(1) {
+      Ok(()) => {}
+      Err(NoRoom::Closed) => {
+        inner.push_wakers.deregister(id);
+        inner.announce_producers();
+        return Err(PushError::Closed(item));
+      }
+      Err(NoRoom::Full) => {
+        inner.push_wakers.register(id, ctx.waker());
+        inner.announce_producers();
+        if !inner.is_full() {
+          ctx.waker().wake_by_ref();
+        }
+        return Err(PushError::Full(item));
+      }
+    }
+    inner.push_wakers.deregister(id);
+    inner.announce_producers();
+    inner.q.push(item);
+    self.release_nodes(inner, 1);
+    Ok(())
+  }
+
+  /// Returns the maximum number of nodes the queue can hold, or `None` if the
+  /// queue is unbounded.
+  pub fn capacity(&self) -> Option<usize> {
+    let inner = lock(&self.q);
+    inner.cap
+  }
+
+  /// Returns the name the queue was given using [`Builder::name()`], if any.
+  pub fn name(&self) -> Option<&str> {
+    self.name.as_deref()
+  }
+
   /// Returns a boolean indicating whether the queue was empty or not.
+  /// Delayed nodes count as well, even if they are not due yet.
   ///
   /// This function is not particularly useful.  If you don't understand why,
   /// then please don't use it.
   pub fn was_empty(&self) -> bool {
-    let q = self.q.lock().unwrap();
-    q.is_empty()
+    let inner = lock(&self.q);
+    inner.len() == 0
+  }
+
+  /// Returns the number of nodes on the queue, including delayed nodes which
+  /// are not due yet.
+  ///
+  /// Like [`was_empty()`](#method.was_empty), the number may well have
+  /// changed by the time the caller gets to look at it.
+  pub fn len(&self) -> usize {
+    let inner = lock(&self.q);
+    inner.len()
+  }
+
+  /// Returns a boolean indicating whether the queue is empty.  Same as
+  /// [`was_empty()`](#method.was_empty).
+  pub fn is_empty(&self) -> bool {
+    self.was_empty()
+  }
+
+  /// Call `f` with the node which would be popped next, without taking it
+  /// off the queue.  Returns `None` if no node is available.
+  ///
+  /// Delayed nodes which are not yet due are not available.
+  ///
+  /// `f` is called while holding the queue lock, so it must not use the
+  /// queue.  With the `lock-free` feature this takes time proportional to the
+  /// length of the queue and blocks pushes and pops; see the [crate
+  /// documentation](crate#features).
+  ///
+  /// ```
+  /// use sigq::Queue;
+  /// let q = Queue::new();
+  /// assert_eq!(q.peek_with(|s: &String| s.len()), None);
+  /// q.push(String::from("hello")).unwrap();
+  /// assert_eq!(q.peek_with(|s| s.len()), Some(5));
+  /// assert_eq!(q.pop().as_deref(), Some("hello"));
+  /// ```
+  pub fn peek_with<R>(&self, f: impl FnOnce(&I) -> R) -> Option<R> {
+    let mut inner = lock(&self.q);
+    let promoted = inner.promote();
+    let delayed = inner.delayed.len();
+    let res = inner.q.edit(delayed, |nodes| nodes.front().map(f));
+    self.release(inner, 0, promoted);
+    res
+  }
+
+  /// Keep only the nodes for which `f` returns `true`, delayed nodes
+  /// included.  The other nodes are removed from the queue and dropped.
+  ///
+  /// If the queue is bounded, then producers waiting for space are woken up
+  /// for the nodes which were removed.
+  ///
+  /// `f` is called while holding the queue lock, so it must not use the
+  /// queue.  The removed nodes are dropped after the lock has been released.
+  /// With the `lock-free` feature producers and consumers are held up until
+  /// all nodes have been looked at.
+  ///
+  /// ```
+  /// use sigq::Queue;
+  /// let q = Queue::new();
+  /// q.push_iter(1..=6).unwrap();
+  /// q.retain(|n| n % 2 == 0);
+  /// assert_eq!(q.len(), 3);
+  /// assert_eq!(q.pop_batch(10), vec![2, 4, 6]);
+  /// ```
+  pub fn retain(&self, f: impl FnMut(&I) -> bool) {
+    let mut inner = lock(&self.q);
+    let removed = inner.remove_unless(f);
+    self.release(inner, removed.len(), 0);
+  }
+
+  /// Remove the first node for which `f` returns `true` from the queue, and
+  /// return it.
+  ///
+  /// Nodes are looked at in the order they would be popped, followed by
+  /// delayed nodes in the order they become due.  If the queue is bounded,
+  /// then a producer waiting for space is woken up.
+  ///
+  /// `f` is called while holding the queue lock, so it must not use the
+  /// queue.  With the `lock-free` feature the whole queue is rebuilt, even if
+  /// the first node matches, and pushes and pops wait for that to finish.
+  ///
+  /// ```
+  /// use sigq::Queue;
+  /// let q = Queue::new();
+  /// q.push_iter(vec!["a", "b", "c"]).unwrap();
+  /// assert_eq!(q.remove_first(|s| *s == "b"), Some("b"));
+  /// assert_eq!(q.remove_first(|s| *s == "b"), None);
+  /// assert_eq!(q.pop_batch(10), vec!["a", "c"]);
+  /// ```
+  pub fn remove_first(&self, f: impl FnMut(&I) -> bool) -> Option<I> {
+    let mut inner = lock(&self.q);
+    let node = inner.remove_first(f)?;
+    self.release(inner, 1, 0);
+    Some(node)
+  }
+
+  /// Return a snapshot of the queue's statistics.
+  ///
+  /// The counters are shared by all handles to the queue, such as the
+  /// [`Sender`] and [`Receiver`] halves of a [`channel()`].
+  ///
+  /// ```
+  /// use sigq::Queue;
+  /// let q = Queue::new();
+  /// q.push_iter(vec![1, 2, 3]).unwrap();
+  /// q.pop();
+  /// let stats = q.stats();
+  /// assert_eq!((stats.pushed, stats.popped), (3, 1));
+  /// assert_eq!((stats.len, stats.high_water), (2, 3));
+  /// ```
+  #[cfg(feature = "stats")]
+  pub fn stats(&self) -> Stats {
+    let inner = lock(&self.q);
+    self.stats.snapshot(inner.len())
+  }
+
+  /// Returns a boolean indicating whether the queue has been closed.
+  pub fn is_closed(&self) -> bool {
+    let inner = lock(&self.q);
+    inner.closed
+  }
+
+  /// Close the queue.
+  ///
+  /// New nodes can no longer be pushed onto a closed queue.  All blocked
+  /// threads and pending async consumers are woken up; they will drain any
+  /// nodes that remain on the queue, after which the pop methods report that
+  /// the queue has been closed.  Delayed nodes are still delivered once they
+  /// become due.
+  ///
+  /// Closing an already closed queue has no effect.
+  ///
+  /// ```
+  /// use sigq::{Queue, TryPopError};
+  /// let q = Queue::new();
+  /// q.push("hello").unwrap();
+  /// q.close();
+  /// assert!(q.push("world").is_err());
+  /// assert_eq!(q.pop(), Some("hello"));
+  /// assert_eq!(q.pop(), None);
+  /// assert_eq!(q.try_pop(), Err(TryPopError::Closed));
+  /// ```
+  pub fn close(&self) {
+    let mut inner = lock(&self.q);
+    if inner.closed {
+      return;
+    }
+    inner.closed = true;
+    inner.q.close();
+    inner.notified = inner.blocked;
+    let wakers = inner.wakers.take_all();
+    let push_wakers = inner.push_wakers.take_all();
+    drop(inner);
+    self.signal.notify_all();
+    self.space.notify_all();
+    for waker in wakers.into_iter().chain(push_wakers) {
+      waker.wake();
+    }
   }
 
   /// Push a node on to the queue and unlock one queue reader, if any.
-  pub fn push(&self, item: I) {
-    let mut q = self.q.lock().unwrap();
-    q.push_back(item);
-    drop(q);
-    self.signal.notify_one();
+  ///
+  /// A blocked thread is woken up if there is one which hasn't been notified
+  /// yet, and a pending async consumer otherwise.
+  ///
+  /// If the queue is bounded and full, then block and wait for space to
+  /// become available.
+  ///
+  /// If the queue has been closed the node is returned in a
+  /// [`PushError::Closed`].
+  pub fn push(&self, item: I) -> Result<(), PushError<I>> {
+    let _span = trace::span(self.name(), "push");
+    #[cfg(feature = "lock-free")]
+    let item = match self.push_unlocked(item) {
+      Err(PushError::Full(item)) => item,
+      res => return res
+    };
+
+    let mut inner = lock(&self.q);
+    loop {
+      match inner.reserve(1) {
+        Ok(()) => break,
+        Err(NoRoom::Closed) => return Err(PushError::Closed(item)),
+        Err(NoRoom::Full) => inner = self.wait_for_space(inner)
+      }
+    }
+    inner.q.push(item);
+    self.release_nodes(inner, 1);
+    Ok(())
+  }
+
+  /// Push a node on to the queue which will not be handed out to any
+  /// consumer until `due`.
+  ///
+  /// Delayed nodes count towards the capacity of a bounded queue.  If the
+  /// queue is bounded and full, then block and wait for space to become
+  /// available.
+  ///
+  /// If the queue has been closed the node is returned in a
+  /// [`PushError::Closed`].
+  ///
+  /// ```
+  /// use std::time::{Duration, Instant};
+  /// use sigq::{Queue, TryPopError};
+  /// let q = Queue::new();
+  /// let due = Instant::now() + Duration::from_millis(20);
+  /// q.push_at("later", due).unwrap();
+  /// q.push("now").unwrap();
+  /// assert_eq!(q.try_pop(), Ok("now"));
+  /// assert_eq!(q.try_pop(), Err(TryPopError::Empty));
+  /// assert_eq!(q.pop(), Some("later"));
+  /// assert!(Instant::now() >= due);
+  /// ```
+  pub fn push_at(&self, item: I, due: Instant) -> Result<(), PushError<I>> {
+    let _span = trace::span(self.name(), "push_at");
+    let mut inner = lock(&self.q);
+    loop {
+      match inner.reserve(1) {
+        Ok(()) => break,
+        Err(NoRoom::Closed) => return Err(PushError::Closed(item)),
+        Err(NoRoom::Full) => inner = self.wait_for_space(inner)
+      }
+    }
+
+    if due <= Instant::now() {
+      inner.q.push(item);
+      self.release_nodes(inner, 1);
+      return Ok(());
+    }
+
+    // Waiting consumers only need to know about the new node if it is the
+    // earliest one.  In that case all of them need to wake up earlier than
+    // they were planning to, since whichever consumer would pick up the node
+    // once it becomes due may have left with another node by then.
+    let is_earliest = match inner.next_due() {
+      Some(next) => due < next,
+      None => true
+    };
+    let seq = inner.delay_seq;
+    inner.delay_seq = seq.wrapping_add(1);
+    inner.delayed.insert((due, seq), item);
+    inner.q.set_delayed(true);
+    self.pushed(1, inner.len());
+    if is_earliest {
+      let wakers = inner.wakers.take_all();
+      drop(inner);
+      self.signal.notify_all();
+      for waker in wakers {
+        waker.wake();
+      }
+    }
+    Ok(())
+  }
+
+  /// Push a node on to the queue which will not be handed out to any
+  /// consumer until `delay` has passed.
+  ///
+  /// A `delay` too long to be represented is treated as the node never
+  /// becoming due; it stays on the queue, counting towards its capacity.
+  ///
+  /// See [`push_at()`](#method.push_at).
+  pub fn push_after(
+    &self,
+    item: I,
+    delay: Duration
+  ) -> Result<(), PushError<I>> {
+    let due = Instant::now().checked_add(delay).unwrap_or_else(far_future);
+    self.push_at(item, due)
+  }
+
+  /// Push a node on to the queue without blocking.
+  ///
+  /// If the queue is bounded and full the node is returned in a
+  /// [`PushError::Full`].  If the queue has been closed the node is returned
+  /// in a [`PushError::Closed`].
+  ///
+  /// ```
+  /// use sigq::{Queue, PushError};
+  /// let q = Queue::bounded(1);
+  /// q.try_push("hello").unwrap();
+  /// match q.try_push("world") {
+  ///   Err(PushError::Full(node)) => assert_eq!(node, "world"),
+  ///   _ => panic!("expected a full queue")
+  /// }
+  /// ```
+  pub fn try_push(&self, item: I) -> Result<(), PushError<I>> {
+    #[cfg(feature = "lock-free")]
+    let item = match self.push_unlocked(item) {
+      Err(PushError::Full(item)) => item,
+      res => return res
+    };
+
+    let mut inner = lock(&self.q);
+    match inner.reserve(1) {
+      Ok(()) => {}
+      Err(NoRoom::Closed) => return Err(PushError::Closed(item)),
+      Err(NoRoom::Full) => return Err(PushError::Full(item))
+    }
+    inner.q.push(item);
+    self.release_nodes(inner, 1);
+    Ok(())
+  }
+
+  /// Push all nodes from an iterator on to the queue.
+  ///
+  /// The nodes are appended while holding the queue lock once, after which as
+  /// many queue readers are unlocked as there are new nodes.  The iterator is
+  /// consumed before the queue is locked.
+  ///
+  /// If the queue is bounded and becomes full, then the nodes pushed so far
+  /// are made available to readers and the call blocks and waits for space to
+  /// become available.
+  ///
+  /// If the queue has been closed, or is closed while waiting for space, then
+  /// the nodes which were not pushed are returned in their original order in
+  /// a [`PushError::Closed`].
+  ///
+  /// ```
+  /// use sigq::Queue;
+  /// let q = Queue::new();
+  /// q.push_iter(vec![1, 2, 3]).unwrap();
+  /// assert_eq!(q.pop_batch(10), vec![1, 2, 3]);
+  /// q.close();
+  /// let rest = q.push_iter(vec![4, 5]).unwrap_err().into_inner();
+  /// assert_eq!(rest, vec![4, 5]);
+  /// ```
+  pub fn push_iter<T>(&self, iter: T) -> Result<(), PushError<VecDeque<I>>>
+  where
+    T: IntoIterator<Item = I>
+  {
+    let _span = trace::span(self.name(), "push_iter");
+    let mut nodes = iter.into_iter().collect::<VecDeque<I>>();
+    if nodes.is_empty() {
+      return Ok(());
+    }
+
+    let mut inner = lock(&self.q);
+    if inner.cap.is_none() {
+      let count = nodes.len();
+      if inner.reserve(count).is_ok() {
+        inner.q.append(nodes);
+        self.release_nodes(inner, count);
+        return Ok(());
+      }
+    }
+
+    let mut count = 0;
+    let res = loop {
+      let node = match nodes.pop_front() {
+        Some(node) => node,
+        None => break Ok(())
+      };
+      match inner.reserve(1) {
+        Ok(()) => {}
+        Err(NoRoom::Closed) => {
+          nodes.push_front(node);
+          break Err(PushError::Closed(nodes));
+        }
+        Err(NoRoom::Full) => {
+          nodes.push_front(node);
+          if count > 0 {
+            // Let readers at the nodes pushed so far before waiting for them
+            // to make room.
+            self.release_nodes(inner, count);
+            count = 0;
+            inner = lock(&self.q);
+          } else {
+            inner = self.wait_for_space(inner);
+          }
+          continue;
+        }
+      }
+      inner.q.push(node);
+      count += 1;
+    };
+    self.release_nodes(inner, count);
+
+    res
+  }
+
+  /// This method serves the same purpose as the [`push()`](#method.push)
+  /// method, but rather than block it returns a `Future` to be used in an
+  /// `async` context.
+  ///
+  /// ```
+  /// use sigq::Queue;
+  /// # futures::executor::block_on(async {
+  /// let q = Queue::bounded(1);
+  /// q.apush("hello").await.unwrap();
+  /// assert_eq!(q.apop().await, Some("hello"));
+  /// # });
+  /// ```
+  pub fn apush(&self, item: I) -> PushFuture<I> {
+    PushFuture::new(self, item)
   }
 
   /// Pull the oldest node off the queue and return it.  If no nodes are
   /// available on the queue, then block and wait for one to become available.
-  pub fn pop(&self) -> I {
-    let mut q = self.q.lock().unwrap();
+  ///
+  /// Returns `None` if the queue is empty and has been closed.
+  pub fn pop(&self) -> Option<I> {
+    let _span = trace::span(self.name(), "pop");
+    #[cfg(feature = "lock-free")]
+    if let Some(node) = self.pop_unlocked() {
+      return Some(node);
+    }
+
+    let mut inner = lock(&self.q);
 
+    let mut id = None;
+    let mut promoted = 0;
     let node = loop {
-      match q.pop_front() {
+      promoted += inner.promote();
+      match inner.take_node(id) {
         Some(node) => {
-          break node;
+          break Some(node);
+        }
+        None if inner.is_drained() => {
+          self.stop_waiting(inner, &mut id);
+          return None;
         }
         None => {
-          q = self.signal.wait(q).unwrap();
+          inner = self.wait(inner, None, &mut id);
         }
       }
     };
-    drop(q);
+    inner.wakers.deregister(&mut id);
+    self.popped(1);
+    self.release(inner, 1, promoted);
 
     node
   }
 
   /// Pull the oldest node off the queue and return it.  If no nodes are
-  /// available on the queue, then return `None`.
-  pub fn try_pop(&self) -> Option<I> {
-    let mut q = self.q.lock().unwrap();
+  /// available on the queue, then block and wait for one to become available,
+  /// but for no longer than `dur`.
+  ///
+  /// Returns [`PopTimeoutError::Timeout`] if no node became available in
+  /// time, and [`PopTimeoutError::Closed`] if the queue is empty and has been
+  /// closed.
+  ///
+  /// ```
+  /// use std::time::Duration;
+  /// use sigq::{Queue, PopTimeoutError};
+  /// let q: Queue<u32> = Queue::new();
+  /// let res = q.pop_timeout(Duration::from_millis(10));
+  /// assert_eq!(res, Err(PopTimeoutError::Timeout));
+  /// ```
+  pub fn pop_timeout(&self, dur: Duration) -> Result<I, PopTimeoutError> {
+    match Instant::now().checked_add(dur) {
+      Some(deadline) => self.pop_deadline(deadline),
+      None => self.pop().ok_or(PopTimeoutError::Closed)
+    }
+  }
 
-    q.pop_front()
+  /// Pull the oldest node off the queue and return it.  If no nodes are
+  /// available on the queue, then block and wait for one to become available,
+  /// but no longer than until `deadline`.
+  ///
+  /// Returns [`PopTimeoutError::Timeout`] if no node became available in
+  /// time, and [`PopTimeoutError::Closed`] if the queue is empty and has been
+  /// closed.
+  pub fn pop_deadline(&self, deadline: Instant) -> Result<I, PopTimeoutError> {
+    let _span = trace::span(self.name(), "pop_deadline");
+    #[cfg(feature = "lock-free")]
+    if let Some(node) = self.pop_unlocked() {
+      return Ok(node);
+    }
+
+    let mut inner = lock(&self.q);
+
+    let mut id = None;
+    let mut promoted = 0;
+    let node = loop {
+      promoted += inner.promote();
+      match inner.take_node(id) {
+        Some(node) => {
+          break node;
+        }
+        None if inner.is_drained() => {
+          self.stop_waiting(inner, &mut id);
+          return Err(PopTimeoutError::Closed);
+        }
+        None => {
+          // Check the remaining time on each iteration, since the wait may end
+          // early due to spurious wakeups, delayed nodes becoming due or
+          // another consumer grabbing the node this thread was woken up for.
+          if Instant::now() >= deadline {
+            self.stop_waiting(inner, &mut id);
+            return Err(PopTimeoutError::Timeout);
+          }
+          inner = self.wait(inner, Some(deadline), &mut id);
+        }
+      }
+    };
+    inner.wakers.deregister(&mut id);
+    self.popped(1);
+    self.release(inner, 1, promoted);
+
+    Ok(node)
+  }
+
+  /// Pull up to `max` of the oldest nodes off the queue in one go.  If no
+  /// nodes are available on the queue, then block and wait for at least one
+  /// to become available.
+  ///
+  /// Returns an empty vector if the queue is empty and has been closed.
+  ///
+  /// # Panics
+  /// Panics if `max` is zero.
+  ///
+  /// ```
+  /// use sigq::Queue;
+  /// let q = Queue::new();
+  /// for n in 0..5 {
+  ///   q.push(n).unwrap();
+  /// }
+  /// assert_eq!(q.pop_batch(3), vec![0, 1, 2]);
+  /// assert_eq!(q.pop_batch(3), vec![3, 4]);
+  /// q.close();
+  /// assert!(q.pop_batch(3).is_empty());
+  /// ```
+  pub fn pop_batch(&self, max: usize) -> Vec<I> {
+    let _span = trace::span(self.name(), "pop_batch");
+    assert!(max > 0, "batch size must be non-zero");
+    let mut inner = lock(&self.q);
+
+    let mut id = None;
+    let mut promoted = 0;
+    let mut nodes = Vec::new();
+    let count = loop {
+      promoted += inner.promote();
+      let count = inner.drain_into(&mut nodes, max, id);
+      if count > 0 {
+        break count;
+      }
+      if inner.is_drained() {
+        self.stop_waiting(inner, &mut id);
+        return nodes;
+      }
+      inner = self.wait(inner, None, &mut id);
+    };
+    inner.wakers.deregister(&mut id);
+    self.popped(count);
+    self.release(inner, count, promoted);
+
+    nodes
+  }
+
+  /// Move up to `max` of the oldest nodes off the queue and append them to
+  /// `buf`, without blocking.  Returns the number of nodes that were moved.
+  ///
+  /// If no nodes are available on the queue, then return
+  /// [`TryPopError::Empty`], or [`TryPopError::Closed`] if the queue has been
+  /// closed.  If `max` is zero no nodes are moved and `Ok(0)` is returned.
+  pub fn try_drain_into(
+    &self,
+    buf: &mut Vec<I>,
+    max: usize
+  ) -> Result<usize, TryPopError> {
+    if max == 0 {
+      return Ok(0);
+    }
+    let mut inner = lock(&self.q);
+
+    let promoted = inner.promote();
+    let count = inner.drain_into(buf, max, None);
+    if count == 0 {
+      if inner.is_drained() {
+        return Err(TryPopError::Closed);
+      }
+      return Err(TryPopError::Empty);
+    }
+    self.popped(count);
+    self.release(inner, count, promoted);
+
+    Ok(count)
+  }
+
+  /// Pull the oldest node off the queue and return it.  If no nodes are
+  /// available on the queue, then return [`TryPopError::Empty`], or
+  /// [`TryPopError::Closed`] if the queue has been closed.
+  ///
+  /// Delayed nodes which are not yet due are not available.
+  pub fn try_pop(&self) -> Result<I, TryPopError> {
+    #[cfg(feature = "lock-free")]
+    if let Some(node) = self.pop_unlocked() {
+      return Ok(node);
+    }
+
+    let mut inner = lock(&self.q);
+
+    let promoted = inner.promote();
+    match inner.take_node(None) {
+      Some(node) => {
+        self.popped(1);
+        self.release(inner, 1, promoted);
+        Ok(node)
+      }
+      None if inner.is_drained() => Err(TryPopError::Closed),
+      None => Err(TryPopError::Empty)
+    }
   }
 
   /// This method serves the same purpose as the [`pop()`](#method.pop) method,
   /// but rather than block it  returns a `Future` to be used in an `async`
   /// context.
   ///
+  /// A pending future registers its task's waker with the queue, and is woken
+  /// up directly by [`push()`](#method.push).  No helper threads are involved.
+  ///
+  /// The future is cancel-safe: a node is only taken off the queue when the
+  /// future resolves to it.  Dropping a pending future takes it off the
+  /// queue's wait list, and if it had already been woken up for a node, the
+  /// wakeup is passed on to another consumer.
+  ///
+  /// The future resolves to `None` if the queue is empty and has been closed.
+  ///
   /// ```
   /// use sigq::Queue;
   /// async fn test() {
   ///   let q = Queue::new();
-  ///   q.push("hello".to_string());
+  ///   q.push("hello".to_string()).unwrap();
   ///   assert_eq!(q.was_empty(), false);
   ///   let node = q.apop().await;
-  ///   assert_eq!(node, "hello");
+  ///   assert_eq!(node, Some("hello".to_string()));
   ///   assert_eq!(q.was_empty(), true);
   /// }
   /// ```
   pub fn apop(&self) -> PopFuture<I> {
     PopFuture::new(self)
   }
+
+  /// This method serves the same purpose as the
+  /// [`pop_batch()`](#method.pop_batch) method, but rather than block it
+  /// returns a `Future` to be used in an `async` context.
+  ///
+  /// # Panics
+  /// Panics if `max` is zero.
+  pub fn apop_batch(&self, max: usize) -> PopBatchFuture<I> {
+    assert!(max > 0, "batch size must be non-zero");
+    PopBatchFuture::new(self, max)
+  }
+
+  /// This method serves the same purpose as the
+  /// [`pop_timeout()`](#method.pop_timeout) method, but rather than block it
+  /// returns a `Future` to be used in an `async` context.
+  ///
+  /// The timeout does not rely on any particular async runtime; it is driven
+  /// by a timer thread owned by the queue, which is spawned the first time an
+  /// async timeout is actually waited on.
+  ///
+  /// ```
+  /// use std::time::Duration;
+  /// use sigq::{Queue, PopTimeoutError};
+  /// # futures::executor::block_on(async {
+  /// let q: Queue<u32> = Queue::new();
+  /// let res = q.apop_timeout(Duration::from_millis(10)).await;
+  /// assert_eq!(res, Err(PopTimeoutError::Timeout));
+  /// # });
+  /// ```
+  pub fn apop_timeout(&self, dur: Duration) -> PopTimeoutFuture<I> {
+    PopTimeoutFuture::new(self, Instant::now().checked_add(dur))
+  }
+
+  /// This method serves the same purpose as the
+  /// [`pop_deadline()`](#method.pop_deadline) method, but rather than block it
+  /// returns a `Future` to be used in an `async` context.
+  pub fn apop_deadline(&self, deadline: Instant) -> PopTimeoutFuture<I> {
+    PopTimeoutFuture::new(self, Some(deadline))
+  }
+}
+
+impl<I> Default for Queue<I> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Push all nodes from an iterator using [`Queue::push_iter()`].
+///
+/// Nodes which can not be pushed because the queue has been closed are
+/// dropped.
+impl<I> Extend<I> for Queue<I> {
+  fn extend<T: IntoIterator<Item = I>>(&mut self, iter: T) {
+    let _ = self.push_iter(iter);
+  }
 }
 
 #[doc(hidden)]
 pub struct PopFuture<I> {
-  signal: Arc<Condvar>,
-  q: Arc<Mutex<VecDeque<I>>>
+  q: Queue<I>,
+  waiter: PopWaiter
 }
 
 impl<I> PopFuture<I> {
   fn new(q: &Queue<I>) -> Self {
     PopFuture {
-      signal: Arc::clone(&q.signal),
-      q: Arc::clone(&q.q)
+      q: q.handle(),
+      waiter: PopWaiter::default()
+    }
+  }
+}
+
+impl<I> Future for PopFuture<I> {
+  type Output = Option<I>;
+  fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+    let this = self.get_mut();
+    this.q.poll_pop(ctx, &mut this.waiter, None)
+  }
+}
+
+impl<I> Drop for PopFuture<I> {
+  fn drop(&mut self) {
+    self.q.abandon_pop(&mut self.waiter);
+  }
+}
+
+#[doc(hidden)]
+pub struct PopBatchFuture<I> {
+  q: Queue<I>,
+  max: usize,
+  waiter: PopWaiter
+}
+
+impl<I> PopBatchFuture<I> {
+  fn new(q: &Queue<I>, max: usize) -> Self {
+    PopBatchFuture {
+      q: q.handle(),
+      max,
+      waiter: PopWaiter::default()
+    }
+  }
+}
+
+impl<I> Future for PopBatchFuture<I> {
+  type Output = Vec<I>;
+  fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+    let this = self.get_mut();
+    this.q.poll_pop_batch(this.max, ctx, &mut this.waiter)
+  }
+}
+
+impl<I> Drop for PopBatchFuture<I> {
+  fn drop(&mut self) {
+    self.q.abandon_pop(&mut self.waiter);
+  }
+}
+
+#[doc(hidden)]
+pub struct PopTimeoutFuture<I> {
+  q: Queue<I>,
+
+  /// `None` if the deadline is too far into the future to be represented.
+  deadline: Option<Instant>,
+  waiter: PopWaiter
+}
+
+impl<I> PopTimeoutFuture<I> {
+  fn new(q: &Queue<I>, deadline: Option<Instant>) -> Self {
+    PopTimeoutFuture {
+      q: q.handle(),
+      deadline,
+      waiter: PopWaiter::default()
     }
   }
 }
 
-impl<I: 'static + Send> Future for PopFuture<I> {
-  type Output = I;
+impl<I> Future for PopTimeoutFuture<I> {
+  type Output = Result<I, PopTimeoutError>;
   fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
-    let mut q = self.q.lock().unwrap();
-    match q.pop_front() {
-      Some(node) => Poll::Ready(node),
+    let this = self.get_mut();
+    let q = &this.q;
+    let _span = trace::span(q.name(), "apop_timeout");
+
+    #[cfg(feature = "lock-free")]
+    if this.waiter.id.is_none() {
+      if let Some(node) = q.pop_unlocked() {
+        return Poll::Ready(Ok(node));
+      }
+    }
+
+    let mut inner = lock(&q.q);
+    let promoted = inner.promote();
+    match inner.take_node(this.waiter.id) {
+      Some(node) => {
+        q.finish_pop(&mut inner, &mut this.waiter);
+        q.popped(1);
+        q.release(inner, 1, promoted);
+        Poll::Ready(Ok(node))
+      }
+      None if inner.is_drained() => {
+        q.stop_waiting(inner, &mut this.waiter.id);
+        q.done_waiting(&mut this.waiter);
+        Poll::Ready(Err(PopTimeoutError::Closed))
+      }
       None => {
-        let waker = ctx.waker().clone();
-        let qc = Arc::clone(&self.q);
-        let signal = Arc::clone(&self.signal);
-        thread::spawn(move || {
-          let mut iq = qc.lock().unwrap();
-          while iq.is_empty() {
-            iq = signal.wait(iq).unwrap();
+        if let Some(deadline) = this.deadline {
+          if Instant::now() >= deadline {
+            q.stop_waiting(inner, &mut this.waiter.id);
+            q.done_waiting(&mut this.waiter);
+            return Poll::Ready(Err(PopTimeoutError::Timeout));
           }
-          drop(iq);
-          waker.wake();
-        });
-        drop(q);
+        }
+        let deadline = this.deadline;
+        q.register_pop_waker(&mut inner, deadline, &mut this.waiter, ctx);
         Poll::Pending
       }
     }
   }
 }
 
+impl<I> Drop for PopTimeoutFuture<I> {
+  fn drop(&mut self) {
+    self.q.abandon_pop(&mut self.waiter);
+  }
+}
+
+#[doc(hidden)]
+pub struct PushFuture<I> {
+  q: Queue<I>,
+  item: Option<I>,
+
+  /// Identifier on the queue's producer wait list.
+  id: Option<u64>
+}
+
+impl<I> PushFuture<I> {
+  fn new(q: &Queue<I>, item: I) -> Self {
+    PushFuture {
+      q: q.handle(),
+      item: Some(item),
+      id: None
+    }
+  }
+}
+
+// The node is only ever moved out of the future, never pinned.
+impl<I> Unpin for PushFuture<I> {}
+
+impl<I> Future for PushFuture<I> {
+  type Output = Result<(), PushError<I>>;
+  fn poll(
+    mut self: Pin<&mut Self>,
+    ctx: &mut Context<'_>
+  ) -> Poll<Self::Output> {
+    let this = &mut *self;
+    let item = this
+      .item
+      .take()
+      .expect("PushFuture polled after completion");
+    match this.q.poll_push(item, ctx, &mut this.id) {
+      Err(PushError::Full(item)) => {
+        this.item = Some(item);
+        Poll::Pending
+      }
+      res => Poll::Ready(res)
+    }
+  }
+}
+
+impl<I> Drop for PushFuture<I> {
+  fn drop(&mut self) {
+    self.q.abandon_push(&mut self.id);
+  }
+}
+
 // vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
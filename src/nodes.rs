@@ -0,0 +1,412 @@
+//! Storage for nodes which are ready to be handed out to consumers.
+//!
+//! By default nodes are kept in a `VecDeque`, and all access goes through the
+//! queue lock.
+//!
+//! With the `lock-free` feature nodes are kept in a lock-free segmented queue
+//! instead, which is shared between the queue lock and the queue handles.
+//! Producers and consumers which don't need to wait can then push and pop
+//! nodes without taking the queue lock, and only take it when somebody needs
+//! to be woken up.  For this to work without losing wakeups:
+//!
+//! - The number of nodes on the queue (delayed nodes and nodes which are about
+//!   to be pushed included) and the closed flag share a single atomic word, so
+//!   a node can't be pushed after the queue has been closed and drained.
+//! - Waiters announce themselves while holding the queue lock and then take
+//!   another look before going to sleep, while producers and consumers which
+//!   don't take the lock check for waiters after having pushed or popped a
+//!   node.  Either the waiter sees the node (or the room), or the other side
+//!   sees the waiter and wakes it up through the queue lock.
+//! - Nodes can only be inspected or removed in place after they have been
+//!   taken off the lock-free queue.  While that is going on an editing flag in
+//!   the same atomic word sends producers to the queue lock, so that nodes
+//!   pushed meanwhile don't end up ahead of the nodes being edited.
+
+#[cfg(not(feature = "lock-free"))]
+use std::collections::VecDeque;
+
+#[cfg(feature = "lock-free")]
+use std::{
+  collections::VecDeque,
+  iter,
+  sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering},
+  sync::Arc,
+  thread
+};
+
+#[cfg(feature = "lock-free")]
+use crossbeam_queue::SegQueue;
+
+/// Reason a node can not be pushed.
+pub(crate) enum NoRoom {
+  Closed,
+  Full
+}
+
+#[cfg(not(feature = "lock-free"))]
+pub(crate) struct Nodes<I> {
+  q: VecDeque<I>
+}
+
+#[cfg(not(feature = "lock-free"))]
+impl<I> Nodes<I> {
+  pub(crate) fn new() -> Self {
+    Nodes { q: VecDeque::new() }
+  }
+
+  /// Number of nodes ready to be handed out.
+  pub(crate) fn len(&self) -> usize {
+    self.q.len()
+  }
+
+  pub(crate) fn is_empty(&self) -> bool {
+    self.q.is_empty()
+  }
+
+  /// Total number of nodes on the queue, given the number of delayed nodes.
+  pub(crate) fn total(&self, delayed: usize) -> usize {
+    self.q.len() + delayed
+  }
+
+  /// Make sure there is room for `n` more nodes (ready or delayed), and
+  /// account for them.
+  pub(crate) fn reserve(
+    &mut self,
+    n: usize,
+    cap: Option<usize>,
+    closed: bool,
+    delayed: usize
+  ) -> Result<(), NoRoom> {
+    if closed {
+      return Err(NoRoom::Closed);
+    }
+    match cap {
+      Some(cap) if self.total(delayed) + n > cap => Err(NoRoom::Full),
+      _ => Ok(())
+    }
+  }
+
+  /// Add a node which has been accounted for using
+  /// [`reserve()`](#method.reserve).
+  pub(crate) fn push(&mut self, item: I) {
+    self.q.push_back(item);
+  }
+
+  /// Add nodes which have been accounted for using
+  /// [`reserve()`](#method.reserve).
+  pub(crate) fn append(&mut self, mut nodes: VecDeque<I>) {
+    self.q.append(&mut nodes);
+  }
+
+  pub(crate) fn pop(&mut self) -> Option<I> {
+    self.q.pop_front()
+  }
+
+  /// Move up to `max` of the oldest nodes into `buf`, returning the number of
+  /// nodes moved.
+  pub(crate) fn drain_into(&mut self, buf: &mut Vec<I>, max: usize) -> usize {
+    let count = max.min(self.q.len());
+    buf.extend(self.q.drain(..count));
+    count
+  }
+
+  /// Run `f` on the nodes which are ready to be handed out, given the number
+  /// of delayed nodes.  `f` may remove nodes, but must not add any.
+  pub(crate) fn edit<R>(
+    &mut self,
+    _delayed: usize,
+    f: impl FnOnce(&mut VecDeque<I>) -> R
+  ) -> R {
+    f(&mut self.q)
+  }
+
+  /// Give back the room accounted for by `n` delayed nodes which have been
+  /// removed.
+  pub(crate) fn unreserve(&mut self, _n: usize) {}
+
+  pub(crate) fn close(&mut self) {}
+
+  pub(crate) fn set_delayed(&mut self, _delayed: bool) {}
+
+  /// Tell the other side how many consumers are waiting.
+  pub(crate) fn announce_consumers(&self, _count: usize) {}
+
+  /// Tell the other side how many producers are waiting.
+  pub(crate) fn announce_producers(&self, _count: usize) {}
+}
+
+/// Set in [`Shared::state`] once the queue has been closed.
+#[cfg(feature = "lock-free")]
+const CLOSED: usize = 1;
+
+/// Set in [`Shared::state`] while nodes are being edited.
+#[cfg(feature = "lock-free")]
+const EDITING: usize = 2;
+
+/// Amount [`Shared::state`] changes by per node.
+#[cfg(feature = "lock-free")]
+const ONE: usize = 4;
+
+#[cfg(feature = "lock-free")]
+struct Shared<I> {
+  q: SegQueue<I>,
+
+  /// Number of nodes on the queue, including delayed nodes and nodes which
+  /// have been accounted for but not yet pushed, times [`ONE`], plus
+  /// [`CLOSED`] once the queue has been closed, plus [`EDITING`] while nodes
+  /// are being edited.
+  state: AtomicUsize,
+
+  /// Maximum number of nodes the queue may hold, if bounded.
+  cap: Option<usize>,
+
+  /// Set if nodes must not be popped without the queue lock; either because
+  /// consumers are served in order, because delayed nodes need to be moved
+  /// onto the queue once they are due, or because nodes are being edited.
+  locked_pop: AtomicBool,
+
+  /// Serve consumers in the order they started waiting.
+  fair: bool,
+
+  /// Number of consumers waiting for a node.
+  consumers: AtomicUsize,
+
+  /// Number of producers waiting for room.
+  producers: AtomicUsize
+}
+
+#[cfg(feature = "lock-free")]
+pub(crate) struct Nodes<I> {
+  shared: Arc<Shared<I>>
+}
+
+#[cfg(feature = "lock-free")]
+impl<I> Nodes<I> {
+  pub(crate) fn new(cap: Option<usize>, fair: bool) -> Self {
+    Nodes {
+      shared: Arc::new(Shared {
+        q: SegQueue::new(),
+        state: AtomicUsize::new(0),
+        cap,
+        locked_pop: AtomicBool::new(fair),
+        fair,
+        consumers: AtomicUsize::new(0),
+        producers: AtomicUsize::new(0)
+      })
+    }
+  }
+
+  /// Return a new handle to the same nodes.
+  pub(crate) fn handle(&self) -> Self {
+    Nodes {
+      shared: Arc::clone(&self.shared)
+    }
+  }
+
+  /// Number of nodes ready to be handed out.
+  pub(crate) fn len(&self) -> usize {
+    self.shared.q.len()
+  }
+
+  pub(crate) fn is_empty(&self) -> bool {
+    self.shared.q.is_empty()
+  }
+
+  /// Total number of nodes on the queue.  Delayed nodes are already counted.
+  pub(crate) fn total(&self, _delayed: usize) -> usize {
+    self.shared.state.load(Ordering::SeqCst) / ONE
+  }
+
+  /// Make sure there is room for `n` more nodes (ready or delayed), and
+  /// account for them.  Whether the queue is bounded, closed or holds
+  /// delayed nodes is tracked by the shared state.
+  pub(crate) fn reserve(
+    &self,
+    n: usize,
+    _cap: Option<usize>,
+    _closed: bool,
+    _delayed: usize
+  ) -> Result<(), NoRoom> {
+    let state = &self.shared.state;
+    let mut cur = state.load(Ordering::SeqCst);
+    loop {
+      if cur & CLOSED != 0 {
+        return Err(NoRoom::Closed);
+      }
+      // Nodes are being edited under the queue lock, which producers have to
+      // wait for just like for room.
+      if cur & EDITING != 0 {
+        return Err(NoRoom::Full);
+      }
+      if let Some(cap) = self.shared.cap {
+        if cur / ONE + n > cap {
+          return Err(NoRoom::Full);
+        }
+      }
+      match state.compare_exchange_weak(
+        cur,
+        cur + n * ONE,
+        Ordering::SeqCst,
+        Ordering::SeqCst
+      ) {
+        Ok(_) => return Ok(()),
+        Err(actual) => cur = actual
+      }
+    }
+  }
+
+  /// Add a node which has been accounted for using
+  /// [`reserve()`](#method.reserve).
+  pub(crate) fn push(&self, item: I) {
+    self.shared.q.push(item);
+  }
+
+  /// Add nodes which have been accounted for using
+  /// [`reserve()`](#method.reserve).
+  pub(crate) fn append(&self, nodes: VecDeque<I>) {
+    for node in nodes {
+      self.shared.q.push(node);
+    }
+  }
+
+  pub(crate) fn pop(&self) -> Option<I> {
+    let node = self.shared.q.pop()?;
+    self.shared.state.fetch_sub(ONE, Ordering::SeqCst);
+    Some(node)
+  }
+
+  /// Move up to `max` of the oldest nodes into `buf`, returning the number of
+  /// nodes moved.
+  pub(crate) fn drain_into(&self, buf: &mut Vec<I>, max: usize) -> usize {
+    let mut count = 0;
+    while count < max {
+      match self.pop() {
+        Some(node) => buf.push(node),
+        None => break
+      }
+      count += 1;
+    }
+    count
+  }
+
+  /// Run `f` on the nodes which are ready to be handed out, given the number
+  /// of delayed nodes.  `f` may remove nodes, but must not add any.
+  ///
+  /// Must be called while holding the queue lock.  Producers and consumers
+  /// which don't take the lock are sent to it until `f` is done, and those
+  /// that are already under way are waited for, so the nodes can be taken
+  /// off the lock-free queue and put back in the same order.
+  pub(crate) fn edit<R>(
+    &self,
+    delayed: usize,
+    f: impl FnOnce(&mut VecDeque<I>) -> R
+  ) -> R {
+    let shared = &*self.shared;
+    let locked = shared.locked_pop.swap(true, Ordering::SeqCst);
+    shared.state.fetch_or(EDITING, Ordering::SeqCst);
+    while shared.q.len() + delayed != shared.state.load(Ordering::SeqCst) / ONE
+    {
+      thread::yield_now();
+    }
+
+    // Should `f` panic, the nodes are still put back.
+    let nodes = iter::from_fn(|| shared.q.pop()).collect::<VecDeque<I>>();
+    let mut edit = Edit {
+      shared,
+      count: nodes.len(),
+      nodes,
+      locked
+    };
+    f(&mut edit.nodes)
+  }
+
+  /// Give back the room accounted for by `n` delayed nodes which have been
+  /// removed.
+  pub(crate) fn unreserve(&self, n: usize) {
+    self.shared.state.fetch_sub(n * ONE, Ordering::SeqCst);
+  }
+
+  pub(crate) fn close(&self) {
+    self.shared.state.fetch_or(CLOSED, Ordering::SeqCst);
+  }
+
+  /// Record whether there are delayed nodes, in which case nodes must only be
+  /// popped while holding the queue lock.
+  pub(crate) fn set_delayed(&self, delayed: bool) {
+    let locked = delayed || self.shared.fair;
+    self.shared.locked_pop.store(locked, Ordering::SeqCst);
+  }
+
+  /// Tell the other side how many consumers are waiting.  A consumer which
+  /// is about to wait has to take another look for nodes afterwards.
+  pub(crate) fn announce_consumers(&self, count: usize) {
+    self.shared.consumers.store(count, Ordering::SeqCst);
+    fence(Ordering::SeqCst);
+  }
+
+  /// Tell the other side how many producers are waiting.  A producer which
+  /// is about to wait has to take another look for room afterwards.
+  pub(crate) fn announce_producers(&self, count: usize) {
+    self.shared.producers.store(count, Ordering::SeqCst);
+    fence(Ordering::SeqCst);
+  }
+
+  /// Push a node without the queue lock.  Returns `true` if consumers are
+  /// waiting and need to be woken up.
+  pub(crate) fn push_unlocked(&self, item: I) -> Result<bool, (NoRoom, I)> {
+    if let Err(e) = self.reserve(1, None, false, 0) {
+      return Err((e, item));
+    }
+    self.shared.q.push(item);
+    fence(Ordering::SeqCst);
+    Ok(self.shared.consumers.load(Ordering::SeqCst) > 0)
+  }
+
+  /// Pop a node without the queue lock, unless nodes must only be popped
+  /// while holding it.  Also returns `true` if producers are waiting for
+  /// room, or if the queue has been closed and this was its last node while
+  /// consumers are waiting, and they need to be woken up.
+  pub(crate) fn pop_unlocked(&self) -> Option<(I, bool)> {
+    if self.shared.locked_pop.load(Ordering::SeqCst) {
+      return None;
+    }
+    let node = self.shared.q.pop()?;
+    let state = self.shared.state.fetch_sub(ONE, Ordering::SeqCst) - ONE;
+    fence(Ordering::SeqCst);
+    let drained = state & !EDITING == CLOSED;
+    let wake = self.shared.producers.load(Ordering::SeqCst) > 0
+      || (drained && self.shared.consumers.load(Ordering::SeqCst) > 0);
+    Some((node, wake))
+  }
+}
+
+/// Nodes taken off the lock-free queue by [`Nodes::edit()`], which are put
+/// back when dropped.
+#[cfg(feature = "lock-free")]
+struct Edit<'a, I> {
+  shared: &'a Shared<I>,
+
+  /// Number of nodes which were taken off the lock-free queue.
+  count: usize,
+
+  nodes: VecDeque<I>,
+
+  /// Previous value of [`Shared::locked_pop`].
+  locked: bool
+}
+
+#[cfg(feature = "lock-free")]
+impl<I> Drop for Edit<'_, I> {
+  fn drop(&mut self) {
+    let removed = self.count.saturating_sub(self.nodes.len());
+    for node in self.nodes.drain(..) {
+      self.shared.q.push(node);
+    }
+    let state = &self.shared.state;
+    state.fetch_sub(removed * ONE, Ordering::SeqCst);
+    state.fetch_and(!EDITING, Ordering::SeqCst);
+    self.shared.locked_pop.store(self.locked, Ordering::SeqCst);
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,285 @@
+//! Priority queue with the same signaling semantics as [`Queue`].
+//!
+//! [`Queue`]: crate::Queue
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use crate::waitlist::WaitList;
+use crate::{cond_wait, lock, wake_many, PushError, TryPopError};
+
+/// A node along with its priority and insertion order.
+struct Entry<I, P> {
+  prio: P,
+  seq: u64,
+  item: I
+}
+
+impl<I, P: Ord> PartialEq for Entry<I, P> {
+  fn eq(&self, other: &Self) -> bool {
+    self.cmp(other) == Ordering::Equal
+  }
+}
+
+impl<I, P: Ord> Eq for Entry<I, P> {}
+
+impl<I, P: Ord> PartialOrd for Entry<I, P> {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl<I, P: Ord> Ord for Entry<I, P> {
+  /// Higher priorities come first.  Among equal priorities the node that was
+  /// pushed first comes first.
+  fn cmp(&self, other: &Self) -> Ordering {
+    self
+      .prio
+      .cmp(&other.prio)
+      .then_with(|| other.seq.cmp(&self.seq))
+  }
+}
+
+struct Inner<I, P> {
+  /// Nodes waiting to be picked up by a consumer.
+  q: BinaryHeap<Entry<I, P>>,
+
+  /// Sequence number assigned to the next pushed node.
+  seq: u64,
+
+  /// Async consumers waiting for a node to become available.
+  wakers: WaitList,
+
+  /// Number of threads blocked waiting for a node to become available.
+  blocked: usize,
+
+  /// Number of blocked threads which have been notified, but have not picked
+  /// up the queue lock yet.
+  notified: usize,
+
+  /// Set once the queue has been closed.
+  closed: bool
+}
+
+impl<I, P> Inner<I, P> {
+  /// Pick the consumer to wake up for a node, the same way [`Queue`] does: a
+  /// blocked thread which hasn't been notified yet if there is one, and an
+  /// async task otherwise.  Returns the task's waker, or the number of
+  /// threads to notify.
+  ///
+  /// [`Queue`]: crate::Queue
+  fn consumer_to_wake(&mut self) -> (Vec<Waker>, usize) {
+    if self.blocked > self.notified {
+      self.notified += 1;
+      (Vec::new(), 1)
+    } else {
+      (self.wakers.take_one().into_iter().collect(), 0)
+    }
+  }
+}
+
+/// Queue which hands out the node with the highest priority first.
+///
+/// Nodes of equal priority are handed out in the order they were pushed.
+///
+/// ```
+/// use sigq::PriorityQueue;
+/// let q = PriorityQueue::new();
+/// q.push("bulk", 0).unwrap();
+/// q.push("urgent", 9).unwrap();
+/// q.push("more bulk", 0).unwrap();
+/// assert_eq!(q.pop(), Some("urgent"));
+/// assert_eq!(q.pop(), Some("bulk"));
+/// assert_eq!(q.pop(), Some("more bulk"));
+/// ```
+pub struct PriorityQueue<I, P: Ord> {
+  signal: Arc<Condvar>,
+  q: Arc<Mutex<Inner<I, P>>>
+}
+
+impl<I, P: Ord> PriorityQueue<I, P> {
+  /// Create, and return, a new priority queue.
+  pub fn new() -> Self {
+    PriorityQueue {
+      signal: Arc::new(Condvar::new()),
+      q: Arc::new(Mutex::new(Inner {
+        q: BinaryHeap::new(),
+        seq: 0,
+        wakers: WaitList::new(),
+        blocked: 0,
+        notified: 0,
+        closed: false
+      }))
+    }
+  }
+
+  /// Returns a boolean indicating whether the queue was empty or not.
+  ///
+  /// See [`Queue::was_empty()`](crate::Queue::was_empty).
+  pub fn was_empty(&self) -> bool {
+    let inner = lock(&self.q);
+    inner.q.is_empty()
+  }
+
+  /// Returns a boolean indicating whether the queue has been closed.
+  pub fn is_closed(&self) -> bool {
+    let inner = lock(&self.q);
+    inner.closed
+  }
+
+  /// Close the queue.
+  ///
+  /// See [`Queue::close()`](crate::Queue::close).
+  pub fn close(&self) {
+    let mut inner = lock(&self.q);
+    if inner.closed {
+      return;
+    }
+    inner.closed = true;
+    inner.notified = inner.blocked;
+    let wakers = inner.wakers.take_all();
+    drop(inner);
+    self.signal.notify_all();
+    for waker in wakers {
+      waker.wake();
+    }
+  }
+
+  /// Push a node with the given priority on to the queue and wake up one
+  /// consumer, if any; a blocked thread before an async task.
+  ///
+  /// If the queue has been closed the node is returned in a
+  /// [`PushError::Closed`].
+  pub fn push(&self, item: I, prio: P) -> Result<(), PushError<I>> {
+    let mut inner = lock(&self.q);
+    if inner.closed {
+      return Err(PushError::Closed(item));
+    }
+    let seq = inner.seq;
+    inner.seq = seq.wrapping_add(1);
+    inner.q.push(Entry { prio, seq, item });
+    let (wakers, blocked) = inner.consumer_to_wake();
+    drop(inner);
+    wake_many(&self.signal, wakers, blocked);
+    Ok(())
+  }
+
+  /// Pull the node with the highest priority off the queue and return it.  If
+  /// no nodes are available on the queue, then block and wait for one to
+  /// become available.
+  ///
+  /// Returns `None` if the queue is empty and has been closed.
+  pub fn pop(&self) -> Option<I> {
+    let mut inner = lock(&self.q);
+
+    loop {
+      match inner.q.pop() {
+        Some(entry) => {
+          break Some(entry.item);
+        }
+        None if inner.closed => {
+          break None;
+        }
+        None => {
+          inner.blocked += 1;
+          inner = cond_wait(&self.signal, inner);
+          // A spurious wakeup is taken for a notification, which at worst
+          // has the next push notify a thread which isn't needed.
+          inner.notified = inner.notified.saturating_sub(1);
+          inner.blocked -= 1;
+        }
+      }
+    }
+  }
+
+  /// Pull the node with the highest priority off the queue and return it.  If
+  /// no nodes are available on the queue, then return
+  /// [`TryPopError::Empty`], or [`TryPopError::Closed`] if the queue has been
+  /// closed.
+  pub fn try_pop(&self) -> Result<I, TryPopError> {
+    let mut inner = lock(&self.q);
+
+    match inner.q.pop() {
+      Some(entry) => Ok(entry.item),
+      None if inner.closed => Err(TryPopError::Closed),
+      None => Err(TryPopError::Empty)
+    }
+  }
+
+  /// This method serves the same purpose as the [`pop()`](#method.pop) method,
+  /// but rather than block it returns a `Future` to be used in an `async`
+  /// context.
+  pub fn apop(&self) -> PriorityPopFuture<I, P> {
+    PriorityPopFuture::new(self)
+  }
+}
+
+impl<I, P: Ord> Default for PriorityQueue<I, P> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[doc(hidden)]
+pub struct PriorityPopFuture<I, P> {
+  signal: Arc<Condvar>,
+  q: Arc<Mutex<Inner<I, P>>>,
+
+  /// Identifier on the queue's wait list.
+  id: Option<u64>
+}
+
+impl<I, P: Ord> PriorityPopFuture<I, P> {
+  fn new(q: &PriorityQueue<I, P>) -> Self {
+    PriorityPopFuture {
+      signal: Arc::clone(&q.signal),
+      q: Arc::clone(&q.q),
+      id: None
+    }
+  }
+}
+
+impl<I, P: Ord> Future for PriorityPopFuture<I, P> {
+  type Output = Option<I>;
+  fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+    let this = self.get_mut();
+    let mut inner = lock(&this.q);
+    match inner.q.pop() {
+      Some(entry) => {
+        inner.wakers.deregister(&mut this.id);
+        Poll::Ready(Some(entry.item))
+      }
+      None if inner.closed => {
+        inner.wakers.deregister(&mut this.id);
+        Poll::Ready(None)
+      }
+      None => {
+        inner.wakers.register(&mut this.id, ctx.waker());
+        Poll::Pending
+      }
+    }
+  }
+}
+
+/// Dropping a pending future takes it off the wait list.  If it had already
+/// been woken up for a node which is still on the queue, the wakeup is passed
+/// on to another consumer.
+impl<I, P> Drop for PriorityPopFuture<I, P> {
+  fn drop(&mut self) {
+    if self.id.is_none() {
+      return;
+    }
+    let mut inner = lock(&self.q);
+    if inner.wakers.deregister(&mut self.id) && !inner.q.is_empty() {
+      let (wakers, blocked) = inner.consumer_to_wake();
+      drop(inner);
+      wake_many(&self.signal, wakers, blocked);
+    }
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,366 @@
+//! Wait for a node on any of several queues.
+//!
+//! A [`Select`] holds handles to a set of queues, which may carry different
+//! node types.  Each queue is added along with a function which maps its
+//! nodes to a common type, and is identified by the order in which it was
+//! added.
+//!
+//! While waiting, a select is on the consumer wait list of every queue it
+//! holds, just like an async consumer of each of them.  Once it has taken a
+//! node from one queue it leaves the others; should it have been woken up
+//! for a node on one of them in the meantime, the wakeup is passed on to
+//! another consumer of that queue.
+
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{PopTimeoutError, PopWaiter, Queue, TryPopError, Unparker};
+
+/// A queue which is part of a select.
+trait Source<T>: Send {
+  /// Poll the queue for a node, waking the task up at `deadline` if given.
+  /// Returns `Ready(None)` if the queue has been closed and drained.
+  fn poll(
+    &mut self,
+    ctx: &mut Context<'_>,
+    deadline: Option<Instant>
+  ) -> Poll<Option<T>>;
+
+  /// Stop waiting on the queue.
+  fn abandon(&mut self);
+}
+
+struct Entry<I, F> {
+  q: Queue<I>,
+  waiter: PopWaiter,
+  map: F
+}
+
+impl<I, T, F> Source<T> for Entry<I, F>
+where
+  I: Send,
+  F: FnMut(I) -> T + Send
+{
+  fn poll(
+    &mut self,
+    ctx: &mut Context<'_>,
+    deadline: Option<Instant>
+  ) -> Poll<Option<T>> {
+    match self.q.poll_pop(ctx, &mut self.waiter, deadline) {
+      Poll::Ready(node) => Poll::Ready(node.map(&mut self.map)),
+      Poll::Pending => Poll::Pending
+    }
+  }
+
+  fn abandon(&mut self) {
+    self.q.abandon_pop(&mut self.waiter);
+  }
+}
+
+impl<I, F> Drop for Entry<I, F> {
+  fn drop(&mut self) {
+    self.q.abandon_pop(&mut self.waiter);
+  }
+}
+
+
+/// Wait for a node on any of several queues.
+///
+/// By default the queues are checked starting at a random one each time, so
+/// that a busy queue can not keep the others from being served.  A biased
+/// select checks them in the order they were added instead, which makes it
+/// possible to prioritize, for example, a control queue over a data queue.
+///
+/// ```
+/// use sigq::{Queue, Select};
+///
+/// enum Event {
+///   Control(&'static str),
+///   Data(u32)
+/// }
+///
+/// let control = Queue::new();
+/// let data = Queue::new();
+/// let mut sel = Select::new()
+///   .add(&control, Event::Control)
+///   .add(&data, Event::Data)
+///   .biased(true);
+///
+/// data.push(1).unwrap();
+/// control.push("stop").unwrap();
+/// match sel.pop() {
+///   Some((0, Event::Control(cmd))) => assert_eq!(cmd, "stop"),
+///   _ => unreachable!()
+/// }
+/// match sel.pop() {
+///   Some((1, Event::Data(n))) => assert_eq!(n, 1),
+///   _ => unreachable!()
+/// }
+/// ```
+pub struct Select<T> {
+  sources: Vec<Box<dyn Source<T>>>,
+  biased: bool
+}
+
+impl<T> Default for Select<T> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<T> Select<T> {
+  /// Create a select without any queues.
+  pub fn new() -> Self {
+    Select {
+      sources: Vec::new(),
+      biased: false
+    }
+  }
+
+  /// Add a queue, using `map` to turn its nodes into the select's node type.
+  ///
+  /// Queues are identified by the order in which they are added, starting at
+  /// zero.
+  pub fn add<I, F>(mut self, q: &Queue<I>, map: F) -> Self
+  where
+    I: Send + 'static,
+    F: FnMut(I) -> T + Send + 'static
+  {
+    self.sources.push(Box::new(Entry {
+      q: q.handle(),
+      waiter: PopWaiter::default(),
+      map
+    }));
+    self
+  }
+
+  /// Check the queues in the order they were added, rather than starting at
+  /// a random one.
+  pub fn biased(mut self, biased: bool) -> Self {
+    self.biased = biased;
+    self
+  }
+
+  /// Returns the number of queues in the select.
+  pub fn len(&self) -> usize {
+    self.sources.len()
+  }
+
+  /// Returns a boolean indicating whether the select has no queues.
+  pub fn is_empty(&self) -> bool {
+    self.sources.is_empty()
+  }
+
+  /// Take a node off the first queue which has one available, and return it
+  /// along with the index of that queue.  If none of the queues have any
+  /// nodes available, then block and wait for one to become available.
+  ///
+  /// Returns `None` once all queues have been closed and drained.
+  pub fn pop(&mut self) -> Option<(usize, T)> {
+    self.block(None).ok()
+  }
+
+  /// Same as [`pop()`](#method.pop), but wait for no longer than `dur`.
+  ///
+  /// Returns [`PopTimeoutError::Timeout`] if no node became available in
+  /// time, and [`PopTimeoutError::Closed`] if all queues have been closed and
+  /// drained.
+  pub fn pop_timeout(
+    &mut self,
+    dur: Duration
+  ) -> Result<(usize, T), PopTimeoutError> {
+    self.block(Instant::now().checked_add(dur))
+  }
+
+  /// Same as [`pop()`](#method.pop), but wait no longer than until
+  /// `deadline`.
+  pub fn pop_deadline(
+    &mut self,
+    deadline: Instant
+  ) -> Result<(usize, T), PopTimeoutError> {
+    self.block(Some(deadline))
+  }
+
+  /// Take a node off the first queue which has one available without
+  /// waiting.
+  ///
+  /// Returns [`TryPopError::Empty`] if none of the queues have any nodes
+  /// available, and [`TryPopError::Closed`] once all of them have been closed
+  /// and drained.
+  pub fn try_pop(&mut self) -> Result<(usize, T), TryPopError> {
+    let waker = Waker::from(Arc::new(Unparker(thread::current())));
+    let mut ctx = Context::from_waker(&waker);
+    let res = match self.poll_sources(&mut ctx, None) {
+      Poll::Ready(res) => res.ok_or(TryPopError::Closed),
+      Poll::Pending => Err(TryPopError::Empty)
+    };
+    self.abandon();
+    res
+  }
+
+  /// Return a `Future` which resolves to the first node available on any of
+  /// the queues, along with the index of its queue, or `None` once all
+  /// queues have been closed and drained.
+  ///
+  /// The future is cancel-safe: a node is only taken off a queue when the
+  /// future resolves to it.
+  pub fn apop(&mut self) -> SelectFuture<'_, T> {
+    SelectFuture { sel: self }
+  }
+
+  /// Same as [`apop()`](#method.apop), but resolve to
+  /// [`PopTimeoutError::Timeout`] if no node became available within `dur`.
+  pub fn apop_timeout(&mut self, dur: Duration) -> SelectTimeoutFuture<'_, T> {
+    SelectTimeoutFuture {
+      sel: self,
+      deadline: Instant::now().checked_add(dur)
+    }
+  }
+
+  /// Same as [`apop()`](#method.apop), but resolve to
+  /// [`PopTimeoutError::Timeout`] if no node became available by `deadline`.
+  pub fn apop_deadline(
+    &mut self,
+    deadline: Instant
+  ) -> SelectTimeoutFuture<'_, T> {
+    SelectTimeoutFuture {
+      sel: self,
+      deadline: Some(deadline)
+    }
+  }
+
+  /// Block until a node is available on any of the queues, they have all
+  /// been closed and drained, or `deadline` has been reached.
+  fn block(
+    &mut self,
+    deadline: Option<Instant>
+  ) -> Result<(usize, T), PopTimeoutError> {
+    let waker = Waker::from(Arc::new(Unparker(thread::current())));
+    let mut ctx = Context::from_waker(&waker);
+    loop {
+      if let Poll::Ready(res) = self.poll_sources(&mut ctx, None) {
+        return res.ok_or(PopTimeoutError::Closed);
+      }
+      match deadline {
+        Some(deadline) => {
+          let now = Instant::now();
+          if now >= deadline {
+            self.abandon();
+            return Err(PopTimeoutError::Timeout);
+          }
+          thread::park_timeout(deadline - now);
+        }
+        None => thread::park()
+      }
+    }
+  }
+
+  /// Poll the queues, starting at a random one unless the select is biased.
+  /// Once a node has been taken, all other queues are abandoned.
+  ///
+  /// `deadline` is handed to the first queue which is still waiting, so that
+  /// the task is woken up when it has been reached.
+  fn poll_sources(
+    &mut self,
+    ctx: &mut Context<'_>,
+    mut deadline: Option<Instant>
+  ) -> Poll<Option<(usize, T)>> {
+    let count = self.sources.len();
+    let start = if self.biased || count == 0 {
+      0
+    } else {
+      random() % count
+    };
+    let mut drained = 0;
+    for idx in (start..count).chain(0..start) {
+      match self.sources[idx].poll(ctx, deadline) {
+        Poll::Ready(Some(node)) => {
+          for (other, source) in self.sources.iter_mut().enumerate() {
+            if other != idx {
+              source.abandon();
+            }
+          }
+          return Poll::Ready(Some((idx, node)));
+        }
+        Poll::Ready(None) => drained += 1,
+        Poll::Pending => deadline = None
+      }
+    }
+    if drained == count {
+      Poll::Ready(None)
+    } else {
+      Poll::Pending
+    }
+  }
+
+  /// Stop waiting on all queues.
+  fn abandon(&mut self) {
+    for source in &mut self.sources {
+      source.abandon();
+    }
+  }
+}
+
+/// Returns a random number, good enough to pick where to start.
+fn random() -> usize {
+  RandomState::new().build_hasher().finish() as usize
+}
+
+
+#[doc(hidden)]
+pub struct SelectFuture<'a, T> {
+  sel: &'a mut Select<T>
+}
+
+impl<T> Future for SelectFuture<'_, T> {
+  type Output = Option<(usize, T)>;
+  fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+    self.get_mut().sel.poll_sources(ctx, None)
+  }
+}
+
+impl<T> Drop for SelectFuture<'_, T> {
+  fn drop(&mut self) {
+    self.sel.abandon();
+  }
+}
+
+
+#[doc(hidden)]
+pub struct SelectTimeoutFuture<'a, T> {
+  sel: &'a mut Select<T>,
+
+  /// `None` if the deadline is too far into the future to be represented.
+  deadline: Option<Instant>
+}
+
+impl<T> Future for SelectTimeoutFuture<'_, T> {
+  type Output = Result<(usize, T), PopTimeoutError>;
+  fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+    let this = self.get_mut();
+    if let Poll::Ready(res) = this.sel.poll_sources(ctx, this.deadline) {
+      return Poll::Ready(res.ok_or(PopTimeoutError::Closed));
+    }
+    match this.deadline {
+      Some(deadline) if Instant::now() >= deadline => {
+        this.sel.abandon();
+        Poll::Ready(Err(PopTimeoutError::Timeout))
+      }
+      _ => Poll::Pending
+    }
+  }
+}
+
+impl<T> Drop for SelectTimeoutFuture<'_, T> {
+  fn drop(&mut self) {
+    self.sel.abandon();
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
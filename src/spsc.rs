@@ -0,0 +1,518 @@
+//! Bounded queue for exactly one producer and one consumer.
+//!
+//! [`channel()`] returns a [`Sender`] and a [`Receiver`] sharing a fixed size
+//! ring buffer.  Neither handle can be cloned, so only one thread (or task)
+//! ever pushes nodes and only one ever pops them.  This allows nodes to be
+//! pushed and popped without any locking: each side only writes its own
+//! position in the ring buffer, and publishes it to the other side.
+//!
+//! A side only takes a lock when it has to wait, in order to leave its waker
+//! for the other side.  Blocking waits park the thread rather than waiting on
+//! a condition variable.  The waiting side announces itself and then takes
+//! another look at the ring buffer before going to sleep, while the other
+//! side checks for a waiter after having pushed or popped a node.  Either the
+//! waiter sees the node (or the room), or the other side sees the waiter and
+//! wakes it up.
+//!
+//! Dropping either handle closes the queue: the receiver drains the remaining
+//! nodes and then gets end-of-stream, and pushing returns the node in a
+//! [`PushError::Closed`].
+//!
+//! With the `futures-core` feature enabled [`Receiver`] implements
+//! `futures_core::Stream`.
+
+use std::cell::UnsafeCell;
+use std::future::Future;
+use std::mem::MaybeUninit;
+use std::pin::Pin;
+use std::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{lock, PopTimeoutError, PushError, TryPopError, Unparker};
+
+/// Create a queue which can hold at most `cap` nodes and return its sender
+/// and receiver.
+///
+/// ```
+/// use std::thread;
+/// let (mut tx, mut rx) = sigq::spsc::channel(4);
+/// let producer = thread::spawn(move || {
+///   for n in 0..16 {
+///     tx.push(n).unwrap();
+///   }
+/// });
+/// for n in 0..16 {
+///   assert_eq!(rx.pop(), Some(n));
+/// }
+/// producer.join().unwrap();
+/// assert_eq!(rx.pop(), None);
+/// ```
+///
+/// # Panics
+/// Panics if `cap` is zero.
+pub fn channel<I>(cap: usize) -> (Sender<I>, Receiver<I>) {
+  assert!(cap > 0, "bounded queue capacity must be non-zero");
+  let slots = (0..cap)
+    .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+    .collect();
+  let ring = Arc::new(Ring {
+    slots,
+    head: AtomicUsize::new(0),
+    tail: AtomicUsize::new(0),
+    closed: AtomicBool::new(false),
+    consumer: Signal::new(),
+    producer: Signal::new()
+  });
+  let tx = Sender {
+    ring: Arc::clone(&ring)
+  };
+  let rx = Receiver { ring };
+  (tx, rx)
+}
+
+
+/// A side of the queue waiting for the other one.
+struct Signal {
+  /// Set while the side is waiting and needs to be woken up.
+  waiting: AtomicBool,
+
+  /// Waker left by the waiting side.
+  waker: Mutex<Option<Waker>>
+}
+
+impl Signal {
+  fn new() -> Self {
+    Signal {
+      waiting: AtomicBool::new(false),
+      waker: Mutex::new(None)
+    }
+  }
+
+  /// Announce that the side is about to wait.  The caller must take another
+  /// look at the ring buffer afterwards.
+  fn register(&self, waker: &Waker) {
+    {
+      let mut slot = lock(&self.waker);
+      match &mut *slot {
+        Some(w) if w.will_wake(waker) => {}
+        slot => *slot = Some(waker.clone())
+      }
+    }
+    self.waiting.store(true, Ordering::SeqCst);
+    fence(Ordering::SeqCst);
+  }
+
+  /// The side is done waiting.
+  fn cancel(&self) {
+    self.waiting.store(false, Ordering::SeqCst);
+  }
+
+  /// Wake the side up if it is waiting.  The caller must have published the
+  /// change the side is waiting for.
+  fn notify(&self) {
+    fence(Ordering::SeqCst);
+    if self.waiting.load(Ordering::SeqCst)
+      && self.waiting.swap(false, Ordering::SeqCst)
+    {
+      let waker = lock(&self.waker).take();
+      if let Some(waker) = waker {
+        waker.wake();
+      }
+    }
+  }
+
+  /// Block the calling thread until `attempt` returns `Some`, or `deadline`
+  /// has passed, in which case `None` is returned.
+  fn block<T>(
+    &self,
+    deadline: Option<Instant>,
+    mut attempt: impl FnMut() -> Option<T>
+  ) -> Option<T> {
+    if let Some(res) = attempt() {
+      return Some(res);
+    }
+    let waker = Waker::from(Arc::new(Unparker(thread::current())));
+    loop {
+      self.register(&waker);
+      if let Some(res) = attempt() {
+        self.cancel();
+        return Some(res);
+      }
+      match deadline {
+        Some(deadline) => {
+          let now = Instant::now();
+          if now >= deadline {
+            self.cancel();
+            return None;
+          }
+          thread::park_timeout(deadline - now);
+        }
+        None => thread::park()
+      }
+    }
+  }
+}
+
+
+/// Ring buffer shared by the sender and the receiver.
+struct Ring<I> {
+  slots: Box<[UnsafeCell<MaybeUninit<I>>]>,
+
+  /// Number of nodes popped so far.  Only written by the receiver.
+  head: AtomicUsize,
+
+  /// Number of nodes pushed so far.  Only written by the sender.
+  tail: AtomicUsize,
+
+  closed: AtomicBool,
+
+  /// Receiver waiting for a node.
+  consumer: Signal,
+
+  /// Sender waiting for room.
+  producer: Signal
+}
+
+// Each slot is only ever accessed by one side at a time: the sender writes
+// slots between `tail` and `head + cap`, the receiver reads slots between
+// `head` and `tail`, and each side publishes its position only once it is
+// done with a slot.
+unsafe impl<I: Send> Send for Ring<I> {}
+unsafe impl<I: Send> Sync for Ring<I> {}
+
+impl<I> Ring<I> {
+  fn slot(&self, pos: usize) -> *mut MaybeUninit<I> {
+    self.slots[pos % self.slots.len()].get()
+  }
+
+  fn len(&self) -> usize {
+    let tail = self.tail.load(Ordering::Acquire);
+    tail.wrapping_sub(self.head.load(Ordering::Acquire))
+  }
+
+  /// Push a node.  Must only be called by the sender.
+  fn push(&self, item: I) -> Result<(), PushError<I>> {
+    if self.closed.load(Ordering::Acquire) {
+      return Err(PushError::Closed(item));
+    }
+    let tail = self.tail.load(Ordering::Relaxed);
+    let head = self.head.load(Ordering::Acquire);
+    if tail.wrapping_sub(head) == self.slots.len() {
+      return Err(PushError::Full(item));
+    }
+    // SAFETY: The slot is not in use, since the receiver has moved past it.
+    unsafe { (*self.slot(tail)).write(item) };
+    self.tail.store(tail.wrapping_add(1), Ordering::Release);
+    self.consumer.notify();
+    Ok(())
+  }
+
+  /// Pop a node.  Must only be called by the receiver.
+  fn pop(&self) -> Result<I, TryPopError> {
+    let head = self.head.load(Ordering::Relaxed);
+    if head == self.tail.load(Ordering::Acquire) {
+      if !self.closed.load(Ordering::Acquire) {
+        return Err(TryPopError::Empty);
+      }
+      // Nodes pushed before the queue was closed are still handed out.
+      if head == self.tail.load(Ordering::Acquire) {
+        return Err(TryPopError::Closed);
+      }
+    }
+    // SAFETY: The sender has written the slot before moving past it.
+    let node = unsafe { (*self.slot(head)).assume_init_read() };
+    self.head.store(head.wrapping_add(1), Ordering::Release);
+    self.producer.notify();
+    Ok(node)
+  }
+
+  fn close(&self) {
+    self.closed.store(true, Ordering::Release);
+    self.consumer.notify();
+    self.producer.notify();
+  }
+}
+
+impl<I> Drop for Ring<I> {
+  fn drop(&mut self) {
+    let mut head = *self.head.get_mut();
+    let tail = *self.tail.get_mut();
+    while head != tail {
+      // SAFETY: Slots between `head` and `tail` hold nodes which have not
+      // been popped.
+      unsafe { (*self.slot(head)).assume_init_drop() };
+      head = head.wrapping_add(1);
+    }
+  }
+}
+
+
+/// Producer side of a queue created using [`channel()`].
+pub struct Sender<I> {
+  ring: Arc<Ring<I>>
+}
+
+impl<I> Sender<I> {
+  /// Push a node onto the queue.  If the queue is full, then block and wait
+  /// for the receiver to make room for it.
+  ///
+  /// If the queue has been closed the node is returned in a
+  /// [`PushError::Closed`].
+  pub fn push(&mut self, item: I) -> Result<(), PushError<I>> {
+    let ring = &self.ring;
+    let mut item = Some(item);
+    let res = ring.producer.block(None, || {
+      match ring.push(item.take().expect("node already pushed")) {
+        Err(PushError::Full(node)) => {
+          item = Some(node);
+          None
+        }
+        res => Some(res)
+      }
+    });
+    res.expect("blocked without a deadline")
+  }
+
+  /// Push a node onto the queue without waiting for room.
+  ///
+  /// Returns the node in a [`PushError::Full`] if the queue is full, and in a
+  /// [`PushError::Closed`] if it has been closed.
+  pub fn try_push(&mut self, item: I) -> Result<(), PushError<I>> {
+    self.ring.push(item)
+  }
+
+  /// Return a `Future` that pushes a node onto the queue, waiting for the
+  /// receiver to make room for it if the queue is full.
+  ///
+  /// The future is cancel-safe: if it is dropped before it completes, the
+  /// node is dropped along with it and has not been pushed.
+  pub fn apush(&mut self, item: I) -> PushFuture<'_, I> {
+    PushFuture {
+      ring: &self.ring,
+      item: Some(item)
+    }
+  }
+
+  /// Returns the maximum number of nodes the queue can hold.
+  pub fn capacity(&self) -> usize {
+    self.ring.slots.len()
+  }
+
+  /// Returns the number of nodes on the queue.
+  pub fn len(&self) -> usize {
+    self.ring.len()
+  }
+
+  /// Returns a boolean indicating whether the queue is empty.
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Close the queue.  The receiver will drain the remaining nodes and then
+  /// get end-of-stream.
+  pub fn close(&self) {
+    self.ring.close();
+  }
+
+  /// Returns a boolean indicating whether the queue has been closed, either
+  /// explicitly or because the receiver has been dropped.
+  pub fn is_closed(&self) -> bool {
+    self.ring.closed.load(Ordering::Acquire)
+  }
+}
+
+impl<I> Drop for Sender<I> {
+  fn drop(&mut self) {
+    self.ring.close();
+  }
+}
+
+
+/// Consumer side of a queue created using [`channel()`].
+pub struct Receiver<I> {
+  ring: Arc<Ring<I>>
+}
+
+impl<I> Receiver<I> {
+  /// Pull the oldest node off the queue and return it.  If the queue is
+  /// empty, then block and wait for a node to be pushed.
+  ///
+  /// Returns `None` if the queue is empty and has been closed.
+  pub fn pop(&mut self) -> Option<I> {
+    let ring = &self.ring;
+    let res = ring.consumer.block(None, || match ring.pop() {
+      Err(TryPopError::Empty) => None,
+      res => Some(res.ok())
+    });
+    res.expect("blocked without a deadline")
+  }
+
+  /// Pull the oldest node off the queue and return it.  If the queue is
+  /// empty, then block and wait for a node to be pushed, but for no longer
+  /// than `dur`.
+  pub fn pop_timeout(&mut self, dur: Duration) -> Result<I, PopTimeoutError> {
+    match Instant::now().checked_add(dur) {
+      Some(deadline) => self.pop_deadline(deadline),
+      None => self.pop().ok_or(PopTimeoutError::Closed)
+    }
+  }
+
+  /// Pull the oldest node off the queue and return it.  If the queue is
+  /// empty, then block and wait for a node to be pushed, but no longer than
+  /// until `deadline`.
+  pub fn pop_deadline(
+    &mut self,
+    deadline: Instant
+  ) -> Result<I, PopTimeoutError> {
+    let ring = &self.ring;
+    let res = ring.consumer.block(Some(deadline), || match ring.pop() {
+      Ok(node) => Some(Ok(node)),
+      Err(TryPopError::Closed) => Some(Err(PopTimeoutError::Closed)),
+      Err(TryPopError::Empty) => None
+    });
+    res.unwrap_or(Err(PopTimeoutError::Timeout))
+  }
+
+  /// Pull the oldest node off the queue and return it, without waiting for
+  /// one to be pushed.
+  pub fn try_pop(&mut self) -> Result<I, TryPopError> {
+    self.ring.pop()
+  }
+
+  /// Return a `Future` that resolves to the oldest node on the queue, or
+  /// `None` if the queue is empty and has been closed.
+  ///
+  /// The future is cancel-safe: a node is only taken off the queue when the
+  /// future resolves to it.
+  pub fn apop(&mut self) -> PopFuture<'_, I> {
+    PopFuture { ring: &self.ring }
+  }
+
+  /// Returns the number of nodes on the queue.
+  pub fn len(&self) -> usize {
+    self.ring.len()
+  }
+
+  /// Returns a boolean indicating whether the queue is empty.
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Close the queue.  Nodes which are already on the queue can still be
+  /// popped.
+  pub fn close(&self) {
+    self.ring.close();
+  }
+
+  /// Returns a boolean indicating whether the queue has been closed, either
+  /// explicitly or because the sender has been dropped.
+  pub fn is_closed(&self) -> bool {
+    self.ring.closed.load(Ordering::Acquire)
+  }
+}
+
+impl<I> Drop for Receiver<I> {
+  fn drop(&mut self) {
+    self.ring.close();
+  }
+}
+
+#[cfg(feature = "futures-core")]
+impl<I> futures_core::Stream for Receiver<I> {
+  type Item = I;
+  fn poll_next(
+    self: Pin<&mut Self>,
+    ctx: &mut Context<'_>
+  ) -> Poll<Option<Self::Item>> {
+    poll_pop(&self.ring, ctx)
+  }
+}
+
+fn poll_pop<I>(ring: &Ring<I>, ctx: &mut Context<'_>) -> Poll<Option<I>> {
+  let mut registered = false;
+  loop {
+    match ring.pop() {
+      Err(TryPopError::Empty) if registered => return Poll::Pending,
+      Err(TryPopError::Empty) => {
+        ring.consumer.register(ctx.waker());
+        registered = true;
+      }
+      res => {
+        if registered {
+          ring.consumer.cancel();
+        }
+        return Poll::Ready(res.ok());
+      }
+    }
+  }
+}
+
+
+#[doc(hidden)]
+pub struct PopFuture<'a, I> {
+  ring: &'a Ring<I>
+}
+
+impl<I> Future for PopFuture<'_, I> {
+  type Output = Option<I>;
+  fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+    poll_pop(self.ring, ctx)
+  }
+}
+
+impl<I> Drop for PopFuture<'_, I> {
+  fn drop(&mut self) {
+    self.ring.consumer.cancel();
+  }
+}
+
+
+#[doc(hidden)]
+pub struct PushFuture<'a, I> {
+  ring: &'a Ring<I>,
+  item: Option<I>
+}
+
+// The node is never pinned.
+impl<I> Unpin for PushFuture<'_, I> {}
+
+impl<I> Future for PushFuture<'_, I> {
+  type Output = Result<(), PushError<I>>;
+  fn poll(
+    mut self: Pin<&mut Self>,
+    ctx: &mut Context<'_>
+  ) -> Poll<Self::Output> {
+    let ring = self.ring;
+    let mut registered = false;
+    loop {
+      let item = self.item.take().expect("polled after completion");
+      match ring.push(item) {
+        Err(PushError::Full(item)) => {
+          self.item = Some(item);
+          if registered {
+            return Poll::Pending;
+          }
+          ring.producer.register(ctx.waker());
+          registered = true;
+        }
+        res => {
+          if registered {
+            ring.producer.cancel();
+          }
+          return Poll::Ready(res);
+        }
+      }
+    }
+  }
+}
+
+impl<I> Drop for PushFuture<'_, I> {
+  fn drop(&mut self) {
+    self.ring.producer.cancel();
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
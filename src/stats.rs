@@ -0,0 +1,173 @@
+//! Counters which keep track of how a queue is being used.
+//!
+//! With the `stats` feature the counters are atomics, which are updated
+//! without taking the queue lock.  Without it they are empty and all updates
+//! compile to nothing.
+
+use std::sync::Arc;
+
+#[cfg(feature = "stats")]
+use std::{
+  convert::TryFrom,
+  sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+  time::{Duration, Instant}
+};
+
+/// Snapshot of a queue's statistics, as returned by
+/// [`Queue::stats()`](crate::Queue::stats).
+#[cfg(feature = "stats")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+  /// Number of nodes pushed onto the queue, delayed nodes included.
+  pub pushed: u64,
+
+  /// Number of nodes popped off the queue.  Nodes removed using
+  /// [`Queue::retain()`](crate::Queue::retain) or
+  /// [`Queue::remove_first()`](crate::Queue::remove_first) don't count.
+  pub popped: u64,
+
+  /// Number of nodes on the queue, delayed nodes included.
+  pub len: usize,
+
+  /// Largest number of nodes the queue has held.
+  pub high_water: usize,
+
+  /// Number of threads blocked waiting for a node.
+  pub blocked: usize,
+
+  /// Number of pending async consumers, such as `apop()` futures, waiting
+  /// for a node.
+  pub pending: usize,
+
+  /// Total time consumers, blocked threads and async consumers alike, have
+  /// spent waiting for a node.  Consumers which are still waiting only count
+  /// once they are done.
+  pub wait_time: Duration
+}
+
+#[cfg(feature = "stats")]
+#[derive(Default)]
+pub(crate) struct Counters {
+  pushed: AtomicU64,
+  popped: AtomicU64,
+  high_water: AtomicUsize,
+  blocked: AtomicUsize,
+  pending: AtomicUsize,
+
+  /// Total time spent waiting, in nanoseconds.
+  wait_time: AtomicU64
+}
+
+#[cfg(feature = "stats")]
+impl Counters {
+  pub(crate) fn new() -> Arc<Self> {
+    Arc::new(Self::default())
+  }
+
+  /// Count `count` pushed nodes, after which the queue holds `len` nodes.
+  pub(crate) fn pushed(&self, count: usize, len: usize) {
+    self.pushed.fetch_add(count as u64, Ordering::Relaxed);
+    self.high_water.fetch_max(len, Ordering::Relaxed);
+  }
+
+  /// Count `count` popped nodes.
+  pub(crate) fn popped(&self, count: usize) {
+    self.popped.fetch_add(count as u64, Ordering::Relaxed);
+  }
+
+  /// Count a thread as blocked until the returned guard is dropped.
+  pub(crate) fn block(&self) -> Blocked<'_> {
+    self.blocked.fetch_add(1, Ordering::Relaxed);
+    Blocked {
+      counters: self,
+      since: Instant::now()
+    }
+  }
+
+  /// Count an async consumer as pending until the returned guard is
+  /// dropped.
+  pub(crate) fn pend(self: &Arc<Self>) -> Pending {
+    self.pending.fetch_add(1, Ordering::Relaxed);
+    Pending {
+      counters: Arc::clone(self),
+      since: Instant::now()
+    }
+  }
+
+  fn add_wait_time(&self, since: Instant) {
+    let nanos = u64::try_from(since.elapsed().as_nanos()).unwrap_or(u64::MAX);
+    self.wait_time.fetch_add(nanos, Ordering::Relaxed);
+  }
+
+  pub(crate) fn snapshot(&self, len: usize) -> Stats {
+    Stats {
+      pushed: self.pushed.load(Ordering::Relaxed),
+      popped: self.popped.load(Ordering::Relaxed),
+      len,
+      high_water: self.high_water.load(Ordering::Relaxed).max(len),
+      blocked: self.blocked.load(Ordering::Relaxed),
+      pending: self.pending.load(Ordering::Relaxed),
+      wait_time: Duration::from_nanos(self.wait_time.load(Ordering::Relaxed))
+    }
+  }
+}
+
+/// A blocked thread, see [`Counters::block()`].
+#[cfg(feature = "stats")]
+pub(crate) struct Blocked<'a> {
+  counters: &'a Counters,
+  since: Instant
+}
+
+#[cfg(feature = "stats")]
+impl Drop for Blocked<'_> {
+  fn drop(&mut self) {
+    self.counters.blocked.fetch_sub(1, Ordering::Relaxed);
+    self.counters.add_wait_time(self.since);
+  }
+}
+
+/// A pending async consumer, see [`Counters::pend()`].
+#[cfg(feature = "stats")]
+pub(crate) struct Pending {
+  counters: Arc<Counters>,
+  since: Instant
+}
+
+#[cfg(feature = "stats")]
+impl Drop for Pending {
+  fn drop(&mut self) {
+    self.counters.pending.fetch_sub(1, Ordering::Relaxed);
+    self.counters.add_wait_time(self.since);
+  }
+}
+
+#[cfg(not(feature = "stats"))]
+pub(crate) struct Counters;
+
+#[cfg(not(feature = "stats"))]
+impl Counters {
+  pub(crate) fn new() -> Arc<Self> {
+    Arc::new(Counters)
+  }
+
+  pub(crate) fn pushed(&self, _count: usize, _len: usize) {}
+
+  pub(crate) fn popped(&self, _count: usize) {}
+
+  pub(crate) fn block(&self) -> Blocked {
+    Blocked
+  }
+
+  pub(crate) fn pend(self: &Arc<Self>) -> Pending {
+    Pending
+  }
+}
+
+#[cfg(not(feature = "stats"))]
+pub(crate) struct Blocked;
+
+#[cfg(not(feature = "stats"))]
+pub(crate) struct Pending;
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
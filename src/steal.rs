@@ -0,0 +1,428 @@
+//! Per-worker queues which let idle workers steal each other's nodes.
+//!
+//! A [`StealGroup`] holds one deque per worker.  A worker pops nodes off the
+//! front of its own deque, and once that is empty steals from the back of its
+//! siblings' deques, starting with the next one along.  Only when every deque
+//! is empty does it wait.
+//!
+//! Waiting workers, blocked threads and async tasks alike, are shared by the
+//! whole group, so a node pushed onto any member's deque wakes up one idle
+//! worker, just like a node pushed onto a `Queue` wakes up one consumer: a
+//! blocked thread which hasn't been notified yet if there is one, and an
+//! async task otherwise.
+//! This is done without taking a group wide lock on every push:
+//!
+//! - The number of nodes in the group and the closed flag share a single
+//!   atomic word, so a node can't be pushed after the group has been closed
+//!   and drained.
+//! - Workers announce themselves while holding the idle lock and then take
+//!   another look before going to sleep, while producers check for idle
+//!   workers after having pushed a node.  Either the worker sees the node, or
+//!   the producer sees the worker and wakes it up through the idle lock.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+use crate::waitlist::WaitList;
+use crate::{cond_wait, lock, wake_many, PushError, TryPopError};
+
+/// Set in [`Group::state`] once the group has been closed.
+const CLOSED: usize = 1;
+
+/// Amount [`Group::state`] changes by per node.
+const ONE: usize = 2;
+
+/// Idle workers, protected by the idle lock.
+struct Idle {
+  /// Number of threads blocked waiting for a node.
+  blocked: usize,
+
+  /// Number of blocked threads which have been notified, but have not picked
+  /// up the idle lock yet.
+  notified: usize,
+
+  /// Async workers waiting for a node.
+  wakers: WaitList
+}
+
+impl Idle {
+  /// Pick the worker to wake up for a node: a blocked thread which hasn't
+  /// been notified yet if there is one, and an async task otherwise.  Returns
+  /// the task's waker, or the number of threads to notify.
+  fn worker_to_wake(&mut self) -> (Vec<Waker>, usize) {
+    if self.blocked > self.notified {
+      self.notified += 1;
+      (Vec::new(), 1)
+    } else {
+      (self.wakers.take_one().into_iter().collect(), 0)
+    }
+  }
+}
+
+struct Group<I> {
+  deques: Box<[Mutex<VecDeque<I>>]>,
+
+  /// Number of nodes in the group, including nodes which have been accounted
+  /// for but not yet pushed, times [`ONE`], plus [`CLOSED`] once the group
+  /// has been closed.
+  state: AtomicUsize,
+
+  idle: Mutex<Idle>,
+
+  /// Signalled when a node has been pushed, or the group has been closed.
+  signal: Condvar,
+
+  /// Number of idle workers, as last announced while holding the idle lock.
+  sleepers: AtomicUsize
+}
+
+impl<I> Group<I> {
+  /// Account for a new node, unless the group has been closed.
+  fn reserve(&self) -> bool {
+    let mut cur = self.state.load(Ordering::SeqCst);
+    loop {
+      if cur & CLOSED != 0 {
+        return false;
+      }
+      match self.state.compare_exchange_weak(
+        cur,
+        cur + ONE,
+        Ordering::SeqCst,
+        Ordering::SeqCst
+      ) {
+        Ok(_) => return true,
+        Err(actual) => cur = actual
+      }
+    }
+  }
+
+  fn len(&self) -> usize {
+    self.state.load(Ordering::SeqCst) / ONE
+  }
+
+  fn is_drained(&self) -> bool {
+    self.state.load(Ordering::SeqCst) == CLOSED
+  }
+
+  fn push(&self, idx: usize, item: I) -> Result<(), PushError<I>> {
+    if !self.reserve() {
+      return Err(PushError::Closed(item));
+    }
+    lock(&self.deques[idx]).push_back(item);
+    fence(Ordering::SeqCst);
+    if self.sleepers.load(Ordering::SeqCst) > 0 {
+      let (wakers, blocked) = lock(&self.idle).worker_to_wake();
+      wake_many(&self.signal, wakers, blocked);
+    }
+    Ok(())
+  }
+
+  /// Take a node off the front of worker `idx`'s deque, or else off the back
+  /// of one of its siblings' deques.
+  fn take(&self, idx: usize) -> Option<I> {
+    let count = self.deques.len();
+    // Only one deque is locked at a time, so workers stealing from each other
+    // can't deadlock.
+    let own = lock(&self.deques[idx]).pop_front();
+    let node = own.or_else(|| {
+      (1..count).find_map(|n| {
+        let sibling = (idx + n) % count;
+        lock(&self.deques[sibling]).pop_back()
+      })
+    })?;
+    self.state.fetch_sub(ONE, Ordering::SeqCst);
+    Some(node)
+  }
+
+  /// Tell producers how many workers are idle.  A worker which is about to
+  /// wait has to take another look for nodes afterwards.
+  fn announce(&self, idle: &Idle) {
+    let count = idle.blocked + idle.wakers.len();
+    self.sleepers.store(count, Ordering::SeqCst);
+    fence(Ordering::SeqCst);
+  }
+
+  /// Returns `true` if a worker which is about to wait had better take
+  /// another look, because nodes have been pushed or the group has been
+  /// closed.
+  fn ready(&self) -> bool {
+    self.state.load(Ordering::SeqCst) != 0
+  }
+
+  /// Release the idle lock after an async worker has stopped waiting without
+  /// getting a node.  If it had been woken up for a node which may still be
+  /// around, the wakeup is passed on.
+  fn stop_waiting(
+    &self,
+    mut idle: MutexGuard<'_, Idle>,
+    id: &mut Option<u64>
+  ) {
+    let woken = idle.wakers.deregister(id);
+    self.announce(&idle);
+    if woken && self.len() > 0 {
+      let (wakers, blocked) = idle.worker_to_wake();
+      drop(idle);
+      wake_many(&self.signal, wakers, blocked);
+    }
+  }
+
+  fn close(&self) {
+    let mut idle = lock(&self.idle);
+    self.state.fetch_or(CLOSED, Ordering::SeqCst);
+    idle.notified = idle.blocked;
+    let wakers = idle.wakers.take_all();
+    drop(idle);
+    self.signal.notify_all();
+    for waker in wakers {
+      waker.wake();
+    }
+  }
+}
+
+
+/// A set of per-worker queues, where idle workers steal nodes from their
+/// siblings before they wait.
+///
+/// ```
+/// use std::thread;
+/// use sigq::StealGroup;
+///
+/// let group = StealGroup::new(4);
+/// let workers = (0..4)
+///   .map(|idx| {
+///     let worker = group.worker(idx);
+///     thread::spawn(move || {
+///       let mut sum = 0;
+///       while let Some(n) = worker.pop() {
+///         sum += n;
+///       }
+///       sum
+///     })
+///   })
+///   .collect::<Vec<_>>();
+///
+/// // Everything goes to the first worker, but the others help out.
+/// for n in 1..=100 {
+///   group.push_to(0, n).unwrap();
+/// }
+/// group.close();
+/// let total: u32 = workers.into_iter().map(|w| w.join().unwrap()).sum();
+/// assert_eq!(total, 5050);
+/// ```
+pub struct StealGroup<I> {
+  group: Arc<Group<I>>
+}
+
+impl<I> StealGroup<I> {
+  /// Create a group with one queue for each of `workers` workers.
+  ///
+  /// # Panics
+  /// Panics if `workers` is zero.
+  pub fn new(workers: usize) -> Self {
+    assert!(workers > 0, "steal group needs at least one worker");
+    StealGroup {
+      group: Arc::new(Group {
+        deques: (0..workers).map(|_| Mutex::new(VecDeque::new())).collect(),
+        state: AtomicUsize::new(0),
+        idle: Mutex::new(Idle {
+          blocked: 0,
+          notified: 0,
+          wakers: WaitList::new()
+        }),
+        signal: Condvar::new(),
+        sleepers: AtomicUsize::new(0)
+      })
+    }
+  }
+
+  /// Return the handle of worker `idx`.
+  ///
+  /// # Panics
+  /// Panics if `idx` is not less than the number of workers.
+  pub fn worker(&self, idx: usize) -> Worker<I> {
+    assert!(idx < self.workers(), "no such worker");
+    Worker {
+      group: Arc::clone(&self.group),
+      idx
+    }
+  }
+
+  /// Returns the number of workers in the group.
+  pub fn workers(&self) -> usize {
+    self.group.deques.len()
+  }
+
+  /// Push a node onto the back of worker `idx`'s queue, and wake up an idle
+  /// worker, if any.
+  ///
+  /// If the group has been closed the node is returned in a
+  /// [`PushError::Closed`].
+  ///
+  /// # Panics
+  /// Panics if `idx` is not less than the number of workers.
+  pub fn push_to(&self, idx: usize, item: I) -> Result<(), PushError<I>> {
+    assert!(idx < self.workers(), "no such worker");
+    self.group.push(idx, item)
+  }
+
+  /// Returns the number of nodes in all of the group's queues.
+  pub fn len(&self) -> usize {
+    self.group.len()
+  }
+
+  /// Returns a boolean indicating whether all of the group's queues are
+  /// empty.
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Close the group.
+  ///
+  /// New nodes can no longer be pushed.  All idle workers are woken up; they
+  /// will drain any nodes that remain in the group, after which the pop
+  /// methods report that the group has been closed.
+  pub fn close(&self) {
+    self.group.close();
+  }
+
+  /// Returns a boolean indicating whether the group has been closed.
+  pub fn is_closed(&self) -> bool {
+    self.group.state.load(Ordering::SeqCst) & CLOSED != 0
+  }
+}
+
+
+/// A worker's handle to a [`StealGroup`].
+pub struct Worker<I> {
+  group: Arc<Group<I>>,
+  idx: usize
+}
+
+impl<I> Worker<I> {
+  /// Returns the index of the worker within its group.
+  pub fn index(&self) -> usize {
+    self.idx
+  }
+
+  /// Push a node onto the back of the worker's own queue, and wake up an
+  /// idle worker, if any.
+  ///
+  /// If the group has been closed the node is returned in a
+  /// [`PushError::Closed`].
+  pub fn push(&self, item: I) -> Result<(), PushError<I>> {
+    self.group.push(self.idx, item)
+  }
+
+  /// Take the oldest node off the worker's own queue, or else steal the
+  /// newest node off a sibling's queue.  If all queues are empty, then block
+  /// and wait for a node to be pushed onto any of them.
+  ///
+  /// Returns `None` if the group is empty and has been closed.
+  pub fn pop(&self) -> Option<I> {
+    let group = &*self.group;
+    loop {
+      if let Some(node) = group.take(self.idx) {
+        return Some(node);
+      }
+      let mut idle = lock(&group.idle);
+      if group.is_drained() {
+        return None;
+      }
+      idle.blocked += 1;
+      group.announce(&idle);
+      if !group.ready() {
+        idle = cond_wait(&group.signal, idle);
+        // A spurious wakeup is taken for a notification, which at worst has
+        // the next push notify a thread which isn't needed.
+        idle.notified = idle.notified.saturating_sub(1);
+      }
+      idle.blocked -= 1;
+      group.announce(&idle);
+    }
+  }
+
+  /// Same as [`pop()`](#method.pop), but without waiting.
+  pub fn try_pop(&self) -> Result<I, TryPopError> {
+    match self.group.take(self.idx) {
+      Some(node) => Ok(node),
+      None if self.group.is_drained() => Err(TryPopError::Closed),
+      None => Err(TryPopError::Empty)
+    }
+  }
+
+  /// Return a `Future` which resolves to a node taken the same way as
+  /// [`pop()`](#method.pop) does, or `None` if the group is empty and has
+  /// been closed.
+  ///
+  /// The future is cancel-safe: a node is only taken when the future
+  /// resolves to it.
+  pub fn apop(&self) -> StealFuture<I> {
+    StealFuture {
+      group: Arc::clone(&self.group),
+      idx: self.idx,
+      id: None
+    }
+  }
+}
+
+impl<I> Clone for Worker<I> {
+  fn clone(&self) -> Self {
+    Worker {
+      group: Arc::clone(&self.group),
+      idx: self.idx
+    }
+  }
+}
+
+
+#[doc(hidden)]
+pub struct StealFuture<I> {
+  group: Arc<Group<I>>,
+  idx: usize,
+
+  /// Identifier on the group's wait list.
+  id: Option<u64>
+}
+
+impl<I> Future for StealFuture<I> {
+  type Output = Option<I>;
+  fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+    let this = self.get_mut();
+    let group = &*this.group;
+    if let Some(node) = group.take(this.idx) {
+      if this.id.is_some() {
+        let mut idle = lock(&group.idle);
+        idle.wakers.deregister(&mut this.id);
+        group.announce(&idle);
+      }
+      return Poll::Ready(Some(node));
+    }
+    let mut idle = lock(&group.idle);
+    if group.is_drained() {
+      group.stop_waiting(idle, &mut this.id);
+      return Poll::Ready(None);
+    }
+    idle.wakers.register(&mut this.id, ctx.waker());
+    group.announce(&idle);
+    if group.ready() {
+      ctx.waker().wake_by_ref();
+    }
+    Poll::Pending
+  }
+}
+
+impl<I> Drop for StealFuture<I> {
+  fn drop(&mut self) {
+    if self.id.is_some() {
+      self
+        .group
+        .stop_waiting(lock(&self.group.idle), &mut self.id);
+    }
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,164 @@
+//! Minimal runtime-agnostic timer used to wake up async tasks at a deadline.
+//!
+//! Each timer owns a background thread which is spawned the first time a
+//! deadline is scheduled.  The thread sleeps until the earliest deadline, and
+//! wakes up the task that registered it.  It terminates once the timer has
+//! been dropped.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::Waker;
+use std::thread;
+use std::time::Instant;
+
+use crate::{cond_wait, cond_wait_timeout, lock};
+
+/// Identifies a scheduled deadline so it can be refreshed or cancelled.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct TimerKey(Instant, u64);
+
+struct State {
+  /// Scheduled deadlines, ordered by expiry time.
+  entries: BTreeMap<TimerKey, Waker>,
+
+  /// Used to distinguish between entries with the same deadline.
+  next_id: u64,
+
+  /// Set once the background thread has been spawned.
+  running: bool,
+
+  /// Tells the background thread to terminate.
+  shutdown: bool
+}
+
+struct Shared {
+  state: Mutex<State>,
+  signal: Condvar
+}
+
+pub(crate) struct Timer {
+  shared: Arc<Shared>
+}
+
+impl Timer {
+  pub(crate) fn new() -> Self {
+    Timer {
+      shared: Arc::new(Shared {
+        state: Mutex::new(State {
+          entries: BTreeMap::new(),
+          next_id: 0,
+          running: false,
+          shutdown: false
+        }),
+        signal: Condvar::new()
+      })
+    }
+  }
+
+  /// Make sure `waker` is woken up once `deadline` has been reached.
+  ///
+  /// If `key` refers to an entry for the same deadline which is still
+  /// scheduled its waker is refreshed.  Otherwise the entry `key` refers to,
+  /// if any, is removed and a new entry is added.  Returns the key of the
+  /// scheduled entry.
+  pub(crate) fn schedule(
+    &self,
+    key: Option<TimerKey>,
+    deadline: Instant,
+    waker: &Waker
+  ) -> TimerKey {
+    let mut state = lock(&self.shared.state);
+    if let Some(key) = key {
+      if key.0 == deadline {
+        if let Some(w) = state.entries.get_mut(&key) {
+          if !w.will_wake(waker) {
+            w.clone_from(waker);
+          }
+          return key;
+        }
+      } else {
+        state.entries.remove(&key);
+      }
+    }
+
+    let key = TimerKey(deadline, state.next_id);
+    state.next_id = state.next_id.wrapping_add(1);
+
+    // Only kick the background thread if the new entry is the earliest one,
+    // otherwise it is already sleeping for a shorter period of time.
+    let earliest = match state.entries.keys().next() {
+      Some(first) => key < *first,
+      None => true
+    };
+    state.entries.insert(key, waker.clone());
+
+    if !state.running {
+      state.running = true;
+      let shared = Arc::clone(&self.shared);
+      thread::Builder::new()
+        .name("sigq-timer".to_string())
+        .spawn(move || run(&shared))
+        .expect("unable to spawn timer thread");
+    } else if earliest {
+      self.shared.signal.notify_one();
+    }
+
+    key
+  }
+
+  /// Remove a scheduled entry, if it has not already expired.
+  pub(crate) fn cancel(&self, key: TimerKey) {
+    let mut state = lock(&self.shared.state);
+    state.entries.remove(&key);
+  }
+}
+
+impl Drop for Timer {
+  fn drop(&mut self) {
+    let mut state = lock(&self.shared.state);
+    state.shutdown = true;
+    drop(state);
+    self.shared.signal.notify_one();
+  }
+}
+
+/// Background thread: wake up tasks as their deadlines expire.
+fn run(shared: &Shared) {
+  let mut state = lock(&shared.state);
+  loop {
+    if state.shutdown {
+      break;
+    }
+
+    let now = Instant::now();
+    let mut expired = Vec::new();
+    while let Some(key) = state.entries.keys().next().copied() {
+      if key.0 > now {
+        break;
+      }
+      if let Some(waker) = state.entries.remove(&key) {
+        expired.push(waker);
+      }
+    }
+
+    if !expired.is_empty() {
+      // Don't hold the lock while calling into the executors.
+      drop(state);
+      for waker in expired {
+        waker.wake();
+      }
+      state = lock(&shared.state);
+      continue;
+    }
+
+    state = match state.entries.keys().next() {
+      Some(key) => {
+        let dur = key.0 - now;
+        cond_wait_timeout(&shared.signal, state, dur)
+      }
+      None => cond_wait(&shared.signal, state)
+    };
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
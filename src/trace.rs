@@ -0,0 +1,111 @@
+//! Tracing of queue events.
+//!
+//! With the `tracing` feature queues emit `TRACE` level events when nodes are
+//! pushed and popped, when consumers start and stop waiting and when they are
+//! woken up, and blocking calls and future polls run inside spans.  Every
+//! span and event carries the queue's name, if it has been given one using
+//! [`Builder::name()`](crate::Builder::name).  Without the feature all of
+//! this compiles to nothing.
+
+#[cfg(feature = "tracing")]
+use tracing::span::EnteredSpan;
+
+/// Enter a span for operation `op` on a queue.
+#[cfg(feature = "tracing")]
+pub(crate) fn span(queue: Option<&str>, op: &'static str) -> EnteredSpan {
+  tracing::trace_span!("sigq", queue, op).entered()
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn pushed(queue: Option<&str>, count: usize, len: usize) {
+  tracing::trace!(queue, count, len, "pushed");
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn popped(queue: Option<&str>, count: usize) {
+  tracing::trace!(queue, count, "popped");
+}
+
+/// A blocked thread is about to wait for a node.  The returned guard reports
+/// when it is done waiting.
+#[cfg(feature = "tracing")]
+pub(crate) fn block(queue: Option<&str>) -> Blocked<'_> {
+  tracing::trace!(queue, "blocked");
+  Blocked { queue }
+}
+
+/// An async consumer has started waiting for a node.
+#[cfg(feature = "tracing")]
+pub(crate) fn pending(queue: Option<&str>) {
+  tracing::trace!(queue, "pending");
+}
+
+/// An async consumer is being polled again while waiting for a node.
+#[cfg(feature = "tracing")]
+pub(crate) fn repoll(queue: Option<&str>) {
+  tracing::trace!(queue, "polled while pending");
+}
+
+/// An async consumer is done waiting.
+#[cfg(feature = "tracing")]
+pub(crate) fn done_waiting(queue: Option<&str>) {
+  tracing::trace!(queue, "done waiting");
+}
+
+/// `blocked` threads and `pending` async consumers are being woken up.
+#[cfg(feature = "tracing")]
+pub(crate) fn wake(queue: Option<&str>, blocked: usize, pending: usize) {
+  if blocked > 0 || pending > 0 {
+    tracing::trace!(queue, blocked, pending, "waking consumers");
+  }
+}
+
+/// A blocked thread, see [`block()`].
+#[cfg(feature = "tracing")]
+pub(crate) struct Blocked<'a> {
+  queue: Option<&'a str>
+}
+
+#[cfg(feature = "tracing")]
+impl Drop for Blocked<'_> {
+  fn drop(&mut self) {
+    let queue = self.queue;
+    tracing::trace!(queue, "unblocked");
+  }
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Entered;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn span(_queue: Option<&str>, _op: &'static str) -> Entered {
+  Entered
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn pushed(_queue: Option<&str>, _count: usize, _len: usize) {}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn popped(_queue: Option<&str>, _count: usize) {}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn block(_queue: Option<&str>) -> Blocked {
+  Blocked
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn pending(_queue: Option<&str>) {}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn repoll(_queue: Option<&str>) {}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn done_waiting(_queue: Option<&str>) {}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn wake(_queue: Option<&str>, _blocked: usize, _pending: usize) {}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Blocked;
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,127 @@
+//! Bookkeeping for async tasks waiting on a queue condition.
+//!
+//! Each waiting future is assigned an identifier the first time it registers
+//! its waker, which it keeps until it completes or is dropped.  This allows a
+//! future to tell whether it has been woken up (its entry has been taken off
+//! the list) or is still waiting, and to remove itself from the list when it
+//! is dropped.
+//!
+//! Identifiers are handed out in increasing order, so they also tell how long
+//! a waiter has been waiting compared to the others.  In fair mode blocked
+//! threads are put on the list as well.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::task::Waker;
+
+pub(crate) struct WaitList {
+  /// Registered wakers, ordered by the time their futures started waiting.
+  wakers: BTreeMap<u64, Waker>,
+
+  /// Identifiers of all waiters on the list, including those which have been
+  /// woken up but have not deregistered yet.
+  members: BTreeSet<u64>,
+
+  /// Identifier assigned to the next future that starts waiting.
+  next_id: u64
+}
+
+impl WaitList {
+  pub(crate) fn new() -> Self {
+    WaitList {
+      wakers: BTreeMap::new(),
+      members: BTreeSet::new(),
+      next_id: 0
+    }
+  }
+
+  /// Number of waiters on the list, including those which have been woken up
+  /// but have not deregistered yet.
+  pub(crate) fn len(&self) -> usize {
+    self.members.len()
+  }
+
+  /// Register a future's waker.
+  ///
+  /// `id` is the future's identifier; it is assigned on the first call.  A
+  /// future that has been woken up and registers again keeps its original
+  /// place on the list.
+  pub(crate) fn register(&mut self, id: &mut Option<u64>, waker: &Waker) {
+    let id = *id.get_or_insert_with(|| {
+      let id = self.next_id;
+      self.next_id += 1;
+      self.members.insert(id);
+      id
+    });
+    match self.wakers.get_mut(&id) {
+      Some(w) => {
+        if !w.will_wake(waker) {
+          w.clone_from(waker);
+        }
+      }
+      None => {
+        self.wakers.insert(id, waker.clone());
+      }
+    }
+  }
+
+  /// Remove a future from the list.
+  ///
+  /// Returns `true` if the future had been woken up, i.e. it had registered
+  /// but was no longer on the list.
+  pub(crate) fn deregister(&mut self, id: &mut Option<u64>) -> bool {
+    match id.take() {
+      Some(id) => {
+        self.members.remove(&id);
+        self.wakers.remove(&id).is_none()
+      }
+      None => false
+    }
+  }
+
+  /// Take the waker of the future that has been waiting the longest.
+  pub(crate) fn take_one(&mut self) -> Option<Waker> {
+    self.wakers.pop_first().map(|(_, waker)| waker)
+  }
+
+  /// Take the wakers of up to `count` of the futures that have been waiting
+  /// the longest.
+  pub(crate) fn take(&mut self, count: usize) -> Vec<Waker> {
+    let mut wakers = Vec::with_capacity(count.min(self.wakers.len()));
+    while wakers.len() < count {
+      match self.take_one() {
+        Some(waker) => wakers.push(waker),
+        None => break
+      }
+    }
+    wakers
+  }
+
+  /// Take the wakers of those of the `count` longest waiting members which
+  /// have not been woken up yet.
+  pub(crate) fn take_first(&mut self, count: usize) -> Vec<Waker> {
+    let wakers = &mut self.wakers;
+    self
+      .members
+      .iter()
+      .take(count)
+      .filter_map(|id| wakers.remove(id))
+      .collect()
+  }
+
+  /// Returns the number of members which have been waiting longer than the
+  /// one identified by `id`.  A waiter which is not on the list yet would
+  /// have to wait for all of them.
+  pub(crate) fn rank(&self, id: Option<u64>) -> usize {
+    match id {
+      Some(id) => self.members.range(..id).count(),
+      None => self.members.len()
+    }
+  }
+
+  /// Take the wakers of all waiting futures.
+  pub(crate) fn take_all(&mut self) -> Vec<Waker> {
+    std::mem::take(&mut self.wakers).into_values().collect()
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
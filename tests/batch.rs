@@ -0,0 +1,84 @@
+//! Batches hand out up to the requested number of the oldest nodes.
+
+mod common;
+
+use std::sync::Arc;
+use std::task::Poll;
+use std::thread;
+
+use futures::executor::block_on;
+
+use sigq::{PushError, Queue, TryPopError};
+
+use common::{poll, Counter};
+
+#[test]
+fn try_drain_into() {
+  let q = Queue::new();
+  let mut buf = Vec::new();
+  assert_eq!(q.try_drain_into(&mut buf, 3), Err(TryPopError::Empty));
+  q.push_iter(0..5).unwrap();
+
+  assert_eq!(q.try_drain_into(&mut buf, 0), Ok(0));
+  assert_eq!(q.try_drain_into(&mut buf, 3), Ok(3));
+  assert_eq!(buf, vec![0, 1, 2]);
+
+  // Asking for more nodes than there are moves whatever is left.
+  assert_eq!(q.try_drain_into(&mut buf, 10), Ok(2));
+  assert_eq!(buf, vec![0, 1, 2, 3, 4]);
+  assert_eq!(q.try_drain_into(&mut buf, 10), Err(TryPopError::Empty));
+
+  q.push(5).unwrap();
+  q.close();
+  assert_eq!(q.try_drain_into(&mut buf, 10), Ok(1));
+  assert_eq!(q.try_drain_into(&mut buf, 10), Err(TryPopError::Closed));
+  assert_eq!(buf.len(), 6);
+}
+
+#[test]
+fn push_iter_returns_unpushed_nodes() {
+  let q = Queue::new();
+  q.close();
+  let rest = q.push_iter(vec![1, 2, 3]).unwrap_err().into_inner();
+  assert_eq!(rest, vec![1, 2, 3]);
+
+  // A bounded push which is waiting for space when the queue is closed gets
+  // back the nodes it did not get to push.
+  let q = Arc::new(Queue::bounded(2));
+  let producer = {
+    let q = Arc::clone(&q);
+    thread::spawn(move || q.push_iter(0..5))
+  };
+  while q.len() < 2 {
+    thread::yield_now();
+  }
+  q.close();
+  match producer.join().unwrap() {
+    Err(PushError::Closed(rest)) => assert_eq!(rest, vec![2, 3, 4]),
+    _ => panic!("expected the remaining nodes back")
+  }
+  assert_eq!(q.pop_batch(10), vec![0, 1]);
+}
+
+#[test]
+fn apop_batch() {
+  let q = Queue::new();
+  q.push_iter(0..5).unwrap();
+  assert_eq!(block_on(q.apop_batch(3)), vec![0, 1, 2]);
+  assert_eq!(block_on(q.apop_batch(10)), vec![3, 4]);
+
+  // A pending batch is woken up by the next push.
+  let w = Counter::new();
+  let mut fut = q.apop_batch(10);
+  assert!(poll(&mut fut, &w).is_pending());
+  q.push_iter(5..7).unwrap();
+  assert_eq!(w.get(), 1);
+  assert_eq!(poll(&mut fut, &w).map(|nodes| nodes.len()), Poll::Ready(2));
+
+  q.push(7).unwrap();
+  q.close();
+  assert_eq!(block_on(q.apop_batch(10)), vec![7]);
+  assert!(block_on(q.apop_batch(10)).is_empty());
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,168 @@
+//! Every subscriber of a broadcast queue receives every item, and slow
+//! subscribers are dealt with according to the queue's policy.
+
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::Poll;
+use std::thread;
+use std::time::Duration;
+
+use sigq::{
+  Broadcast, PushError, RecvError, SlowSubscriber, Subscriber, TryRecvError
+};
+
+use common::{block_on, poll, Counter};
+
+fn drain<I: Clone>(sub: &mut Subscriber<I>) -> Vec<Result<I, RecvError>> {
+  let mut got = Vec::new();
+  loop {
+    match sub.try_recv() {
+      Ok(item) => got.push(Ok(item)),
+      Err(TryRecvError::Lagged(n)) => got.push(Err(RecvError::Lagged(n))),
+      Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => return got
+    }
+  }
+}
+
+#[test]
+fn every_subscriber_gets_every_item() {
+  const ITEMS: u32 = 2000;
+  let bc = Broadcast::new(8, SlowSubscriber::Block);
+  let subscribers = (0..4)
+    .map(|n| {
+      let mut sub = bc.subscribe();
+      thread::spawn(move || {
+        let mut got = Vec::new();
+        if n % 2 == 0 {
+          while let Ok(item) = sub.recv() {
+            got.push(item);
+          }
+        } else {
+          block_on(async {
+            while let Ok(item) = sub.arecv().await {
+              got.push(item);
+            }
+          });
+        }
+        got
+      })
+    })
+    .collect::<Vec<_>>();
+
+  for n in 0..ITEMS {
+    if n % 2 == 0 {
+      bc.push(n).unwrap();
+    } else {
+      block_on(bc.apush(n)).unwrap();
+    }
+  }
+  drop(bc);
+  for sub in subscribers {
+    assert_eq!(sub.join().unwrap(), (0..ITEMS).collect::<Vec<_>>());
+  }
+}
+
+#[test]
+fn block_waits_for_slowest_subscriber() {
+  let bc = Arc::new(Broadcast::new(2, SlowSubscriber::Block));
+  let mut fast = bc.subscribe();
+  let mut slow = bc.subscribe();
+  bc.push(1).unwrap();
+  bc.push(2).unwrap();
+  assert_eq!(fast.recv(), Ok(1));
+  assert!(matches!(bc.try_push(3), Err(PushError::Full(3))));
+
+  let bc2 = Arc::clone(&bc);
+  let producer = thread::spawn(move || bc2.push(3));
+  thread::sleep(Duration::from_millis(50));
+  assert!(!producer.is_finished());
+  assert_eq!(slow.recv(), Ok(1));
+  producer.join().unwrap().unwrap();
+
+  // A subscriber which goes away no longer holds producers up.
+  assert_eq!(drain(&mut fast), vec![Ok(2), Ok(3)]);
+  assert!(matches!(bc.try_push(4), Err(PushError::Full(4))));
+  drop(slow);
+  bc.push(4).unwrap();
+  assert_eq!(drain(&mut fast), vec![Ok(4)]);
+}
+
+#[test]
+fn drop_oldest_skips_items() {
+  let bc = Broadcast::new(2, SlowSubscriber::DropOldest);
+  let mut sub = bc.subscribe();
+  for n in 0..5 {
+    bc.try_push(n).unwrap();
+  }
+  assert_eq!(drain(&mut sub), vec![Ok(3), Ok(4)]);
+}
+
+#[test]
+fn lag_reports_missed_items() {
+  let bc = Broadcast::new(2, SlowSubscriber::Lag);
+  let mut slow = bc.subscribe();
+  let mut fast = bc.subscribe();
+  for n in 0..5 {
+    bc.push(n).unwrap();
+    assert_eq!(fast.recv(), Ok(n));
+  }
+  assert_eq!(
+    drain(&mut slow),
+    vec![Err(RecvError::Lagged(3)), Ok(3), Ok(4)]
+  );
+}
+
+#[test]
+fn clone_and_late_subscribers() {
+  let bc = Broadcast::new(4, SlowSubscriber::Block);
+  let mut sub = bc.subscribe();
+  bc.push("a").unwrap();
+  let mut twin = sub.clone();
+  let mut late = bc.subscribe();
+  bc.push("b").unwrap();
+  bc.close();
+  assert!(matches!(bc.push("c"), Err(PushError::Closed("c"))));
+
+  assert_eq!(drain(&mut sub), vec![Ok("a"), Ok("b")]);
+  assert_eq!(drain(&mut twin), vec![Ok("a"), Ok("b")]);
+  assert_eq!(drain(&mut late), vec![Ok("b")]);
+  assert_eq!(late.recv(), Err(RecvError::Closed));
+}
+
+/// Items are only cloned for subscribers other than the last one to receive
+/// them.
+#[test]
+fn last_subscriber_gets_the_original() {
+  struct Item(Arc<AtomicUsize>);
+  impl Clone for Item {
+    fn clone(&self) -> Self {
+      self.0.fetch_add(1, Ordering::SeqCst);
+      Item(Arc::clone(&self.0))
+    }
+  }
+
+  let clones = Arc::new(AtomicUsize::new(0));
+  let bc = Broadcast::new(4, SlowSubscriber::Block);
+  let mut subs = (0..3).map(|_| bc.subscribe()).collect::<Vec<_>>();
+  bc.push(Item(Arc::clone(&clones))).unwrap();
+  for sub in &mut subs {
+    sub.recv().unwrap();
+  }
+  assert_eq!(clones.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn pending_arecv_is_woken() {
+  let bc = Broadcast::new(4, SlowSubscriber::Block);
+  let mut sub = bc.subscribe();
+  let w = Counter::new();
+  let mut fut = sub.arecv();
+  assert!(poll(&mut fut, &w).is_pending());
+  bc.push(1).unwrap();
+  assert_eq!(w.get(), 1);
+  assert_eq!(poll(&mut fut, &w), Poll::Ready(Ok(1)));
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,36 @@
+//! Channel handles close the queue once either side has gone away.
+
+use std::thread;
+use std::time::Duration;
+
+use sigq::{bounded_channel, channel, PushError};
+
+#[test]
+fn push_after_last_receiver_dropped() {
+  let (tx, rx) = channel();
+  let rx2 = rx.clone();
+  drop(rx);
+  assert!(tx.push(String::from("hello")).is_ok());
+  drop(rx2);
+  assert!(tx.is_closed());
+  match tx.push(String::from("world")) {
+    Err(PushError::Closed(node)) => assert_eq!(node, "world"),
+    _ => panic!("expected push to report a closed queue")
+  }
+}
+
+#[test]
+fn blocked_sender_wakes_when_last_receiver_dropped() {
+  let (tx, rx) = bounded_channel(1);
+  tx.push(1).unwrap();
+  let sender = thread::spawn(move || tx.push(2));
+  thread::sleep(Duration::from_millis(20));
+  assert!(!sender.is_finished());
+  drop(rx);
+  match sender.join().unwrap() {
+    Err(PushError::Closed(node)) => assert_eq!(node, 2),
+    _ => panic!("expected blocked push to report a closed queue")
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,61 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+
+/// Waker which counts how many times it has been woken up.
+pub struct Counter(AtomicUsize);
+
+impl Counter {
+  pub fn new() -> Arc<Self> {
+    Arc::new(Counter(AtomicUsize::new(0)))
+  }
+
+  pub fn get(&self) -> usize {
+    self.0.load(Ordering::SeqCst)
+  }
+}
+
+impl Wake for Counter {
+  fn wake(self: Arc<Self>) {
+    self.0.fetch_add(1, Ordering::SeqCst);
+  }
+}
+
+/// Waker which unparks the thread running a future.
+struct Unparker(thread::Thread);
+
+impl Wake for Unparker {
+  fn wake(self: Arc<Self>) {
+    self.0.unpark();
+  }
+}
+
+pub fn poll<F>(fut: &mut F, waker: &Arc<Counter>) -> Poll<F::Output>
+where
+  F: Future + Unpin
+{
+  let waker = Waker::from(Arc::clone(waker));
+  Pin::new(fut).poll(&mut Context::from_waker(&waker))
+}
+
+/// Minimal executor: run a future to completion on the current thread.
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+  let mut fut = Box::pin(fut);
+  let waker = Waker::from(Arc::new(Unparker(thread::current())));
+  let mut ctx = Context::from_waker(&waker);
+  loop {
+    if let Poll::Ready(res) = fut.as_mut().poll(&mut ctx) {
+      return res;
+    }
+    thread::park();
+  }
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,28 @@
+//! Delayed nodes are held back until they become due.
+
+use std::time::Duration;
+
+use sigq::{channel, PopTimeoutError, Queue, TryPopError};
+
+#[test]
+fn delay_too_long_to_represent() {
+  let q = Queue::new();
+  q.push_after("never", Duration::MAX).unwrap();
+  q.push_after("soon", Duration::from_millis(10)).unwrap();
+  assert_eq!(q.try_pop(), Err(TryPopError::Empty));
+  assert_eq!(q.pop_timeout(Duration::from_secs(5)), Ok("soon"));
+  assert_eq!(
+    q.pop_timeout(Duration::from_millis(20)),
+    Err(PopTimeoutError::Timeout)
+  );
+  assert_eq!(q.len(), 1);
+  assert_eq!(q.remove_first(|_| true), Some("never"));
+
+  let (tx, rx) = channel();
+  tx.push_after(1, Duration::MAX).unwrap();
+  tx.push(2).unwrap();
+  assert_eq!(rx.pop(), Some(2));
+  assert_eq!(rx.try_pop(), Err(TryPopError::Empty));
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,275 @@
+//! Durable queues recover unacknowledged nodes from their logs.
+
+#![cfg(feature = "durable")]
+
+mod common;
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use sigq::{DurableOptions, DurablePushError, DurableQueue, TryPopError};
+
+use common::block_on;
+
+/// Log path which is removed, along with the files next to it, when
+/// dropped.
+struct TempLog(PathBuf);
+
+impl TempLog {
+  fn new(name: &str) -> Self {
+    let log = TempLog(std::env::temp_dir().join(format!(
+      "sigq-durable-{}-{}.log",
+      name,
+      std::process::id()
+    )));
+    log.remove();
+    log
+  }
+
+  fn with_suffix(&self, suffix: &str) -> PathBuf {
+    let mut path = self.0.clone().into_os_string();
+    path.push(suffix);
+    PathBuf::from(path)
+  }
+
+  fn remove(&self) {
+    let _ = fs::remove_file(&self.0);
+    let _ = fs::remove_file(self.with_suffix(".lock"));
+    let _ = fs::remove_dir_all(self.with_suffix(".tmp"));
+  }
+}
+
+impl Drop for TempLog {
+  fn drop(&mut self) {
+    self.remove();
+  }
+}
+
+fn drain(q: &DurableQueue<u32>) -> Vec<u32> {
+  let mut nodes = Vec::new();
+  while let Ok(mut node) = q.try_pop() {
+    node.ack().unwrap();
+    nodes.push(node.into_inner());
+  }
+  nodes
+}
+
+#[test]
+fn recovers_unacked_nodes() {
+  let log = TempLog::new("recover");
+  let q = DurableQueue::open(&log.0).unwrap();
+  for n in 0..5 {
+    q.push(n).unwrap();
+  }
+  let mut zero = q.pop().unwrap();
+  zero.ack().unwrap();
+  zero.ack().unwrap();
+  assert!(zero.is_acked());
+  let one = q.pop().unwrap();
+  assert_eq!(*one, 1);
+  assert_eq!(one.into_inner(), 1);
+  assert_eq!(q.len(), 3);
+  drop(zero);
+  drop(q);
+
+  // Node 1 was popped but never acknowledged.
+  let q = DurableQueue::open(&log.0).unwrap();
+  assert_eq!(drain(&q), vec![1, 2, 3, 4]);
+  q.push(5).unwrap();
+  drop(q);
+
+  let q = DurableQueue::open(&log.0).unwrap();
+  assert_eq!(drain(&q), vec![5]);
+  assert_eq!(q.try_pop().err(), Some(TryPopError::Empty));
+}
+
+#[test]
+fn opened_once() {
+  let log = TempLog::new("once");
+  let q = DurableQueue::open(&log.0).unwrap();
+  q.push(1).unwrap();
+  let err = DurableQueue::<u32>::open(&log.0).err().unwrap();
+  assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+  // A delivery keeps the log open after the queue has been dropped.
+  let one = q.pop().unwrap();
+  drop(q);
+  assert!(DurableQueue::<u32>::open(&log.0).is_err());
+  drop(one);
+
+  let q = DurableQueue::open(&log.0).unwrap();
+  q.push(2).unwrap();
+  drop(q);
+  let q = DurableQueue::open(&log.0).unwrap();
+  assert_eq!(drain(&q), vec![1, 2]);
+}
+
+#[test]
+fn dropped_delivery_is_requeued() {
+  let log = TempLog::new("requeue");
+  let q = DurableQueue::open(&log.0).unwrap();
+  for n in 0..3 {
+    q.push(n).unwrap();
+  }
+  let zero = q.pop().unwrap();
+  let mut one = q.pop().unwrap();
+  one.ack().unwrap();
+  drop(one);
+  drop(zero);
+
+  // The unacknowledged node goes to the end of the queue.
+  assert_eq!(q.len(), 2);
+  assert_eq!(drain(&q), vec![2, 0]);
+
+  // A blocked consumer picks up a node which is put back.
+  q.push(3).unwrap();
+  let three = q.pop().unwrap();
+  let consumer = {
+    let q = Arc::new(q);
+    let q2 = Arc::clone(&q);
+    (q, thread::spawn(move || q2.pop().map(|d| d.into_inner())))
+  };
+  thread::sleep(Duration::from_millis(50));
+  drop(three);
+  assert_eq!(consumer.1.join().unwrap(), Some(3));
+
+  // Once the queue has been closed the node is only left in the log.
+  let q = consumer.0;
+  q.push(4).unwrap();
+  let four = q.pop().unwrap();
+  q.close();
+  drop(four);
+  assert!(q.pop().is_none());
+  drop(q);
+
+  let q = DurableQueue::open(&log.0).unwrap();
+  assert_eq!(drain(&q), vec![3, 4]);
+}
+
+#[test]
+fn closed_queue() {
+  let log = TempLog::new("closed");
+  let q = DurableQueue::open(&log.0).unwrap();
+  q.push(1).unwrap();
+  q.close();
+  assert!(q.is_closed());
+  match q.push(2) {
+    Err(DurablePushError::Closed(2)) => {}
+    _ => panic!("expected push onto closed queue to fail")
+  }
+  assert_eq!(drain(&q), vec![1]);
+  assert!(q.pop().is_none());
+}
+
+#[test]
+fn torn_tail() {
+  let log = TempLog::new("torn");
+  let q = DurableQueue::open(&log.0).unwrap();
+  q.push(1).unwrap();
+  q.push(2).unwrap();
+  drop(q);
+
+  // Simulate a record which was only partly written.
+  let len = fs::metadata(&log.0).unwrap().len();
+  let mut file = OpenOptions::new().append(true).open(&log.0).unwrap();
+  file.write_all(&[13, 0, 0, 0, 1, 2]).unwrap();
+  drop(file);
+  assert!(fs::metadata(&log.0).unwrap().len() > len);
+
+  let q = DurableQueue::open(&log.0).unwrap();
+  assert_eq!(fs::metadata(&log.0).unwrap().len(), len);
+  q.push(3).unwrap();
+  drop(q);
+
+  let q = DurableQueue::open(&log.0).unwrap();
+  assert_eq!(drain(&q), vec![1, 2, 3]);
+}
+
+#[test]
+fn compaction() {
+  let log = TempLog::new("compact");
+  let q = DurableOptions::new().compact_after(8).open(&log.0).unwrap();
+  for n in 0..16 {
+    q.push(n).unwrap();
+  }
+  let full = fs::metadata(&log.0).unwrap().len();
+  for _ in 0..8 {
+    q.pop().unwrap().ack().unwrap();
+  }
+
+  // Only the eight remaining nodes are left in the log.
+  assert_eq!(fs::metadata(&log.0).unwrap().len(), full / 2);
+  drop(q);
+
+  // Records are copied from where the previous compaction left them.
+  let q = DurableOptions::new().compact_after(8).open(&log.0).unwrap();
+  for n in 16..20 {
+    q.push(n).unwrap();
+  }
+  for _ in 0..8 {
+    q.pop().unwrap().ack().unwrap();
+  }
+  assert_eq!(fs::metadata(&log.0).unwrap().len(), full / 4);
+  q.push(20).unwrap();
+  q.compact().unwrap();
+  drop(q);
+
+  let q = DurableQueue::open(&log.0).unwrap();
+  assert_eq!(drain(&q), (16..21).collect::<Vec<_>>());
+  q.compact().unwrap();
+  assert_eq!(fs::metadata(&log.0).unwrap().len(), 0);
+}
+
+/// A compaction which fails leaves the old log in place, and is reported by
+/// the acknowledgement which set it off.
+#[test]
+fn failed_compaction() {
+  let log = TempLog::new("failed");
+  let q = DurableOptions::new().compact_after(2).open(&log.0).unwrap();
+  for n in 0..5 {
+    q.push(n).unwrap();
+  }
+  let len = fs::metadata(&log.0).unwrap().len();
+
+  // Keep the new log from being created.
+  fs::create_dir(log.with_suffix(".tmp")).unwrap();
+  q.pop().unwrap().ack().unwrap();
+  let mut one = q.pop().unwrap();
+  assert!(one.ack().is_err());
+  assert!(one.is_acked());
+  assert!(fs::metadata(&log.0).unwrap().len() > len);
+  drop(one);
+  q.push(5).unwrap();
+
+  // The next acknowledgement tries again.
+  fs::remove_dir(log.with_suffix(".tmp")).unwrap();
+  q.pop().unwrap().ack().unwrap();
+  assert!(fs::metadata(&log.0).unwrap().len() < len);
+  q.push(6).unwrap();
+  drop(q);
+
+  let q = DurableQueue::open(&log.0).unwrap();
+  assert_eq!(drain(&q), vec![3, 4, 5, 6]);
+}
+
+#[test]
+fn apop() {
+  let log = TempLog::new("apop");
+  let q = DurableQueue::open(&log.0).unwrap();
+  q.push(String::from("hello")).unwrap();
+  let mut node = block_on(q.apop()).unwrap();
+  assert_eq!(*node, "hello");
+  node.ack().unwrap();
+  q.close();
+  assert!(block_on(q.apop()).is_none());
+  drop((q, node));
+
+  let q: DurableQueue<String> = DurableQueue::open(&log.0).unwrap();
+  assert!(q.is_empty());
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
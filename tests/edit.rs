@@ -0,0 +1,119 @@
+//! Nodes can be looked at and removed in place, without upsetting waiting
+//! producers and consumers or the order of nodes pushed meanwhile.
+
+mod common;
+
+use std::sync::Arc;
+use std::task::Poll;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sigq::{Queue, TryPopError};
+
+use common::{poll, Counter};
+
+/// Removing nodes from a full bounded queue makes room for waiting
+/// producers.
+#[test]
+fn removal_wakes_producers() {
+  let q = Arc::new(Queue::bounded(2));
+  q.push(1).unwrap();
+  q.push(2).unwrap();
+  let q2 = Arc::clone(&q);
+  let producer = thread::spawn(move || q2.push(3));
+  let w = Counter::new();
+  let mut fut = q.apush(4);
+  assert!(poll(&mut fut, &w).is_pending());
+  thread::sleep(Duration::from_millis(50));
+
+  q.retain(|n| *n > 2);
+  producer.join().unwrap().unwrap();
+  assert_eq!(w.get(), 1);
+  assert!(poll(&mut fut, &w).is_ready());
+  assert_eq!(q.len(), 2);
+
+  let mut fut = q.apush(5);
+  assert!(poll(&mut fut, &w).is_pending());
+  assert_eq!(q.remove_first(|n| *n == 4), Some(4));
+  assert_eq!(w.get(), 2);
+  assert!(poll(&mut fut, &w).is_ready());
+  assert_eq!(q.pop_batch(10), vec![3, 5]);
+}
+
+/// Consumers waiting on a closed queue are told once the last node has been
+/// removed.
+#[test]
+fn removing_last_node_of_closed_queue() {
+  let q = Queue::new();
+  q.push_after("stale", Duration::from_secs(60)).unwrap();
+  q.close();
+  let w = Counter::new();
+  let mut fut = q.apop();
+  assert!(poll(&mut fut, &w).is_pending());
+  q.retain(|_| false);
+  assert!(q.is_empty());
+  assert_eq!(poll(&mut fut, &w), Poll::Ready(None));
+  assert_eq!(q.try_pop(), Err(TryPopError::Closed));
+}
+
+/// Delayed nodes can be removed, and are not visible to
+/// [`Queue::peek_with()`] until they are due.
+#[test]
+fn delayed_nodes() {
+  let q = Queue::bounded(3);
+  let due = Instant::now() + Duration::from_millis(20);
+  q.push_at("b", due).unwrap();
+  q.push_at("a", due + Duration::from_secs(60)).unwrap();
+  q.push("c").unwrap();
+  assert_eq!(q.peek_with(|s| *s), Some("c"));
+  assert_eq!(q.remove_first(|s| *s == "a"), Some("a"));
+  assert_eq!(q.len(), 2);
+  assert_eq!(q.pop(), Some("c"));
+
+  assert_eq!(q.peek_with(|s| *s), None);
+  thread::sleep(Duration::from_millis(30));
+  assert_eq!(q.peek_with(|s| *s), Some("b"));
+  q.retain(|s| *s != "b");
+  assert!(q.is_empty());
+}
+
+/// Nodes which are pushed or popped while other nodes are being removed
+/// keep their order.
+#[test]
+fn concurrent_edits_keep_order() {
+  const NODES: usize = 20_000;
+  let q = Arc::new(Queue::new());
+  let q2 = Arc::clone(&q);
+  let producer = thread::spawn(move || {
+    for n in 0..NODES {
+      q2.push(n).unwrap();
+    }
+    q2.close();
+  });
+  let q2 = Arc::clone(&q);
+  let consumer = thread::spawn(move || {
+    let mut got = Vec::new();
+    while let Some(n) = q2.pop() {
+      got.push(n);
+    }
+    got
+  });
+
+  let mut removed = 0;
+  while !producer.is_finished() {
+    q.retain(|n| {
+      let keep = n % 3 != 0;
+      if !keep {
+        removed += 1;
+      }
+      keep
+    });
+    q.peek_with(|_| ());
+  }
+  producer.join().unwrap();
+  let got = consumer.join().unwrap();
+  assert!(got.windows(2).all(|w| w[0] < w[1]));
+  assert_eq!(got.len() + removed, NODES);
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
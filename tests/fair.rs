@@ -0,0 +1,141 @@
+//! Consumers of a fair queue are served in the order they started waiting,
+//! whether they are blocked threads or async tasks.
+
+mod common;
+
+use std::sync::Arc;
+use std::task::Poll;
+use std::thread;
+use std::time::Duration;
+
+use sigq::{Builder, Queue, TryPopError};
+
+use common::{poll, Counter};
+
+fn fair<I>() -> Arc<Queue<I>> {
+  Arc::new(Builder::new().fair(true).build())
+}
+
+/// Give a freshly spawned thread time to start waiting.
+fn settle() {
+  thread::sleep(Duration::from_millis(50));
+}
+
+/// The line is formed by polling futures in a fixed order, so it does not
+/// depend on how threads happen to be scheduled.
+#[test]
+fn consumers_served_in_order() {
+  let q = fair();
+  let mut line = (0..4)
+    .map(|_| {
+      let w = Counter::new();
+      let mut fut = q.apop();
+      assert!(poll(&mut fut, &w).is_pending());
+      (fut, w)
+    })
+    .collect::<Vec<_>>();
+
+  for n in 0..4 {
+    q.push(n).unwrap();
+
+    // Only the consumer at the front of the line is woken up, and the ones
+    // behind it can't take the node even if they are polled first.
+    for (fut, w) in line.iter_mut().skip(1).rev() {
+      assert_eq!(w.get(), 0);
+      assert!(poll(fut, w).is_pending());
+    }
+    let (mut fut, w) = line.remove(0);
+    assert_eq!(w.get(), 1);
+    assert_eq!(poll(&mut fut, &w), Poll::Ready(Some(n)));
+  }
+}
+
+#[test]
+fn newcomer_does_not_barge() {
+  let q = fair();
+  let q2 = Arc::clone(&q);
+  let consumer = thread::spawn(move || q2.pop());
+  settle();
+
+  q.push("first").unwrap();
+  assert_eq!(q.try_pop(), Err(TryPopError::Empty));
+  assert_eq!(consumer.join().unwrap(), Some("first"));
+}
+
+#[test]
+fn sync_and_async_share_the_line() {
+  let q = fair();
+  let w = Counter::new();
+  let mut fut = q.apop();
+  assert!(poll(&mut fut, &w).is_pending());
+
+  let q2 = Arc::clone(&q);
+  let consumer = thread::spawn(move || q2.pop());
+  settle();
+
+  // The task started waiting first, so the thread has to let it have the
+  // first node even though it is blocked and the task is not being polled.
+  q.push(1).unwrap();
+  settle();
+  assert_eq!(w.get(), 1);
+  assert!(!consumer.is_finished());
+  assert_eq!(poll(&mut fut, &w), Poll::Ready(Some(1)));
+
+  q.push(2).unwrap();
+  assert_eq!(consumer.join().unwrap(), Some(2));
+}
+
+#[test]
+fn dropped_task_hands_node_on() {
+  let q = fair();
+  let w = Counter::new();
+  let mut fut = q.apop();
+  assert!(poll(&mut fut, &w).is_pending());
+
+  let q2 = Arc::clone(&q);
+  let consumer = thread::spawn(move || q2.pop());
+  settle();
+
+  q.push(1).unwrap();
+  drop(fut);
+  assert_eq!(consumer.join().unwrap(), Some(1));
+}
+
+#[test]
+fn timed_out_waiter_leaves_the_line() {
+  let q = fair();
+  let q2 = Arc::clone(&q);
+  let first =
+    thread::spawn(move || q2.pop_timeout(Duration::from_millis(100)));
+  settle();
+  let q2 = Arc::clone(&q);
+  let second = thread::spawn(move || q2.pop());
+  settle();
+
+  assert!(first.join().unwrap().is_err());
+  q.push(1).unwrap();
+  assert_eq!(second.join().unwrap(), Some(1));
+}
+
+#[test]
+fn close_releases_everyone_in_line() {
+  let q = fair();
+  let consumers = (0..3)
+    .map(|_| {
+      let q = Arc::clone(&q);
+      let consumer = thread::spawn(move || q.pop());
+      settle();
+      consumer
+    })
+    .collect::<Vec<_>>();
+
+  q.push(1).unwrap();
+  q.close();
+  let got = consumers
+    .into_iter()
+    .map(|c| c.join().unwrap())
+    .collect::<Vec<_>>();
+  assert_eq!(got, vec![Some(1), None, None]);
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,61 @@
+//! A panic while the queue lock is held leaves the queue usable.
+
+mod common;
+
+use std::sync::Arc;
+use std::task::Poll;
+use std::thread;
+use std::time::Duration;
+
+use sigq::{Queue, TryPopError};
+
+use common::{poll, Counter};
+
+#[test]
+fn panic_in_retain() {
+  let q = Arc::new(Queue::new());
+  let consumer = {
+    let q = Arc::clone(&q);
+    thread::spawn(move || q.pop())
+  };
+  let w = Counter::new();
+  let mut fut = q.apop();
+  assert!(poll(&mut fut, &w).is_pending());
+  thread::sleep(Duration::from_millis(50));
+
+  // The delayed node makes retain() call the closure while both consumers
+  // are waiting.
+  q.push_after(0, Duration::from_secs(60)).unwrap();
+  let res = {
+    let q = Arc::clone(&q);
+    thread::spawn(move || q.retain(|_| panic!("oops"))).join()
+  };
+  assert!(res.is_err());
+  assert_eq!(q.len(), 1);
+
+  q.push(1).unwrap();
+  q.push(2).unwrap();
+  let mut got = vec![consumer.join().unwrap().unwrap()];
+  match poll(&mut fut, &w) {
+    Poll::Ready(Some(node)) => got.push(node),
+    _ => panic!("expected the async consumer to get a node")
+  }
+  got.sort_unstable();
+  assert_eq!(got, vec![1, 2]);
+
+  q.push(3).unwrap();
+  let res = {
+    let q = Arc::clone(&q);
+    thread::spawn(move || q.peek_with(|_: &u32| -> u32 { panic!("oops") }))
+      .join()
+  };
+  assert!(res.is_err());
+  assert_eq!(q.try_pop(), Ok(3));
+
+  q.close();
+  assert_eq!(q.remove_first(|_| true), Some(0));
+  assert_eq!(q.try_pop(), Err(TryPopError::Closed));
+  assert_eq!(q.pop(), None);
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,97 @@
+//! Priority queues hand out the highest priority first, and nodes of equal
+//! priority in the order they were pushed.
+
+mod common;
+
+use std::sync::Arc;
+use std::task::Poll;
+use std::thread;
+use std::time::Duration;
+
+use futures::executor::block_on;
+
+use sigq::{PriorityQueue, TryPopError};
+
+use common::{poll, Counter};
+
+#[test]
+fn fifo_among_equal_priorities() {
+  let q = PriorityQueue::new();
+  for n in 0..100 {
+    q.push(n, n % 3).unwrap();
+  }
+  let mut nodes = Vec::new();
+  while let Ok(n) = q.try_pop() {
+    nodes.push(n);
+  }
+  let expected = [2, 1, 0]
+    .iter()
+    .flat_map(|prio| (0..100).filter(move |n| n % 3 == *prio))
+    .collect::<Vec<_>>();
+  assert_eq!(nodes, expected);
+}
+
+#[test]
+fn apop() {
+  let q = PriorityQueue::new();
+  q.push("low", 1).unwrap();
+  q.push("high", 5).unwrap();
+  assert_eq!(block_on(q.apop()), Some("high"));
+  assert_eq!(block_on(q.apop()), Some("low"));
+
+  // A pending future is woken up by the next push.
+  let w = Counter::new();
+  let mut fut = q.apop();
+  assert!(poll(&mut fut, &w).is_pending());
+  q.push("later", 0).unwrap();
+  assert_eq!(w.get(), 1);
+  assert_eq!(poll(&mut fut, &w), Poll::Ready(Some("later")));
+}
+
+/// A push wakes up a blocked thread rather than a pending async consumer,
+/// and not both.
+#[test]
+fn push_wakes_one_consumer() {
+  let q = Arc::new(PriorityQueue::new());
+  let w = Counter::new();
+  let mut fut = q.apop();
+  assert!(poll(&mut fut, &w).is_pending());
+  let consumer = {
+    let q = Arc::clone(&q);
+    thread::spawn(move || q.pop())
+  };
+  thread::sleep(Duration::from_millis(50));
+
+  q.push(1, 0).unwrap();
+  assert_eq!(consumer.join().unwrap(), Some(1));
+  assert_eq!(w.get(), 0);
+  q.push(2, 0).unwrap();
+  assert_eq!(w.get(), 1);
+  assert_eq!(poll(&mut fut, &w), Poll::Ready(Some(2)));
+}
+
+#[test]
+fn close_wakes_parked_consumers() {
+  let q = Arc::new(PriorityQueue::<u32, u32>::new());
+  let consumers = (0..2)
+    .map(|_| {
+      let q = Arc::clone(&q);
+      thread::spawn(move || q.pop())
+    })
+    .collect::<Vec<_>>();
+  let w = Counter::new();
+  let mut fut = q.apop();
+  assert!(poll(&mut fut, &w).is_pending());
+  thread::sleep(Duration::from_millis(50));
+
+  q.close();
+  for consumer in consumers {
+    assert_eq!(consumer.join().unwrap(), None);
+  }
+  assert_eq!(w.get(), 1);
+  assert_eq!(poll(&mut fut, &w), Poll::Ready(None));
+  assert_eq!(q.try_pop(), Err(TryPopError::Closed));
+  assert!(q.push(1, 1).is_err());
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
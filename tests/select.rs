@@ -0,0 +1,166 @@
+//! Selecting over several queues hands out each node exactly once, and does
+//! not keep nodes from other consumers of the same queues.
+
+mod common;
+
+use std::sync::Arc;
+use std::task::Poll;
+use std::thread;
+use std::time::Duration;
+
+use sigq::{PopTimeoutError, Queue, Select, TryPopError};
+
+use common::{block_on, poll, Counter};
+
+#[derive(Debug, PartialEq)]
+enum Event {
+  Control(&'static str),
+  Data(u32)
+}
+
+#[test]
+fn blocking_select_wakes_for_any_queue() {
+  let control = Arc::new(Queue::new());
+  let data = Arc::new(Queue::new());
+  let mut sel = Select::new()
+    .add(&control, Event::Control)
+    .add(&data, Event::Data);
+
+  let data2 = Arc::clone(&data);
+  let producer = thread::spawn(move || {
+    thread::sleep(Duration::from_millis(50));
+    data2.push(7).unwrap();
+  });
+  assert_eq!(sel.pop(), Some((1, Event::Data(7))));
+  producer.join().unwrap();
+
+  control.push("stop").unwrap();
+  assert_eq!(
+    sel.pop_timeout(Duration::from_secs(5)),
+    Ok((0, Event::Control("stop")))
+  );
+}
+
+#[test]
+fn biased_select_prefers_earlier_queues() {
+  let (a, b) = (Queue::new(), Queue::new());
+  let mut sel = Select::new().add(&a, |n| n).add(&b, |n| n).biased(true);
+  for n in 0..3 {
+    a.push(n).unwrap();
+    b.push(n + 10).unwrap();
+  }
+  let got = (0..6).map(|_| sel.pop().unwrap()).collect::<Vec<_>>();
+  assert_eq!(got, vec![(0, 0), (0, 1), (0, 2), (1, 10), (1, 11), (1, 12)]);
+}
+
+#[test]
+fn random_select_serves_every_queue() {
+  let (a, b) = (Queue::new(), Queue::new());
+  let mut sel = Select::new().add(&a, |n| n).add(&b, |n| n);
+  for n in 0..200 {
+    a.push(n).unwrap();
+    b.push(n).unwrap();
+  }
+  let from_b = (0..200).filter(|_| sel.pop().unwrap().0 == 1).count();
+  assert!(from_b > 0 && from_b < 200);
+}
+
+#[test]
+fn timeout_and_closed() {
+  let (a, b) = (Queue::<u32>::new(), Queue::<u32>::new());
+  let mut sel = Select::new().add(&a, |n| n).add(&b, |n| n);
+  assert_eq!(
+    sel.pop_timeout(Duration::from_millis(20)),
+    Err(PopTimeoutError::Timeout)
+  );
+  assert_eq!(
+    block_on(sel.apop_timeout(Duration::from_millis(20))),
+    Err(PopTimeoutError::Timeout)
+  );
+
+  assert_eq!(sel.try_pop(), Err(TryPopError::Empty));
+
+  // A select is only closed once every queue has been closed and drained.
+  b.push(1).unwrap();
+  a.close();
+  assert_eq!(sel.try_pop(), Ok((1, 1)));
+  assert_eq!(sel.try_pop(), Err(TryPopError::Empty));
+  b.push(2).unwrap();
+  b.close();
+  assert_eq!(sel.try_pop(), Ok((1, 2)));
+  assert_eq!(sel.try_pop(), Err(TryPopError::Closed));
+  assert_eq!(sel.pop(), None);
+  assert_eq!(block_on(sel.apop()), None);
+}
+
+/// A select which is dropped after having been woken up for a node must pass
+/// the wakeup on to the queue's other consumers.
+#[test]
+fn dropped_select_passes_wakeup_on() {
+  let (a, b) = (Queue::new(), Queue::<u32>::new());
+  let mut sel = Select::new().add(&a, |n| n).add(&b, |n| n);
+  let (w1, w2) = (Counter::new(), Counter::new());
+
+  let mut fut1 = sel.apop();
+  assert!(poll(&mut fut1, &w1).is_pending());
+  let mut fut2 = a.apop();
+  assert!(poll(&mut fut2, &w2).is_pending());
+
+  a.push(1).unwrap();
+  assert_eq!((w1.get(), w2.get()), (1, 0));
+  drop(fut1);
+  assert_eq!(w2.get(), 1);
+  assert_eq!(poll(&mut fut2, &w2), Poll::Ready(Some(1)));
+}
+
+/// Several selects and plain consumers compete for the nodes of two queues,
+/// and every node is delivered exactly once.
+#[test]
+fn competing_consumers() {
+  const NODES: u32 = 5000;
+  let (a, b) = (Arc::new(Queue::new()), Arc::new(Queue::new()));
+
+  let mut consumers = Vec::new();
+  for n in 0..4 {
+    let mut sel = Select::new().add(&a, |n| n).add(&b, |n| n + NODES);
+    consumers.push(thread::spawn(move || {
+      let mut got = Vec::new();
+      if n % 2 == 0 {
+        while let Some((_, node)) = sel.pop() {
+          got.push(node);
+        }
+      } else {
+        block_on(async {
+          while let Some((_, node)) = sel.apop().await {
+            got.push(node);
+          }
+        });
+      }
+      got
+    }));
+  }
+  let a2 = Arc::clone(&a);
+  consumers.push(thread::spawn(move || {
+    let mut got = Vec::new();
+    while let Some(node) = a2.pop() {
+      got.push(node);
+    }
+    got
+  }));
+
+  for n in 0..NODES {
+    a.push(n).unwrap();
+    b.push(n).unwrap();
+  }
+  a.close();
+  b.close();
+
+  let mut all = consumers
+    .into_iter()
+    .flat_map(|c| c.join().unwrap())
+    .collect::<Vec<_>>();
+  all.sort_unstable();
+  assert_eq!(all, (0..2 * NODES).collect::<Vec<_>>());
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,69 @@
+//! Senders accept nodes as a `Sink`, with backpressure from bounded queues.
+
+#![cfg(feature = "futures-sink")]
+
+mod common;
+
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use futures::executor::block_on;
+use futures::{stream, SinkExt, StreamExt};
+
+use sigq::{bounded_channel, channel, ClosedError};
+
+use common::Counter;
+
+#[test]
+fn poll_ready_applies_backpressure() {
+  let (mut tx, rx) = bounded_channel(1);
+  let w = Counter::new();
+  let waker = Waker::from(Arc::clone(&w));
+  let mut ctx = Context::from_waker(&waker);
+
+  assert!(tx.poll_ready_unpin(&mut ctx).is_ready());
+  tx.start_send_unpin(1).unwrap();
+  assert!(tx.poll_ready_unpin(&mut ctx).is_ready());
+  tx.start_send_unpin(2).unwrap();
+
+  // The queue is full, so the second node stays with the sender.
+  assert!(tx.poll_ready_unpin(&mut ctx).is_pending());
+  assert_eq!(rx.pop(), Some(1));
+  assert_eq!(w.get(), 1);
+  assert!(tx.poll_ready_unpin(&mut ctx).is_ready());
+  assert_eq!(rx.try_pop(), Ok(2));
+}
+
+#[test]
+fn send_all() {
+  let (mut tx, rx) = bounded_channel(2);
+  let consumer = thread::spawn(move || {
+    let mut nodes = Vec::new();
+    while let Some(n) = rx.pop() {
+      nodes.push(n);
+    }
+    nodes
+  });
+  let mut nodes = stream::iter(0..100).map(Ok);
+  block_on(tx.send_all(&mut nodes)).unwrap();
+  drop(tx);
+  assert_eq!(consumer.join().unwrap(), (0..100).collect::<Vec<_>>());
+}
+
+#[test]
+fn poll_close_closes_queue() {
+  let (mut tx, rx) = channel();
+  block_on(tx.feed("hello")).unwrap();
+  block_on(tx.close()).unwrap();
+  assert!(rx.is_closed());
+  assert_eq!(rx.pop(), Some("hello"));
+  assert_eq!(rx.pop(), None);
+
+  let w = Counter::new();
+  let waker = Waker::from(Arc::clone(&w));
+  let mut ctx = Context::from_waker(&waker);
+  assert_eq!(tx.poll_ready_unpin(&mut ctx), Poll::Ready(Err(ClosedError)));
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,136 @@
+//! Nodes pushed through a single-producer single-consumer queue arrive in
+//! order, whichever mix of blocking and async waiting is used on each side.
+
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::Poll;
+use std::thread;
+use std::time::Duration;
+
+use sigq::{spsc, PopTimeoutError, PushError, TryPopError};
+
+use common::{block_on, poll, Counter};
+
+const NODES: usize = 100_000;
+
+fn stream(cap: usize, async_push: bool, async_pop: bool) {
+  let (mut tx, mut rx) = spsc::channel(cap);
+  let producer = thread::spawn(move || {
+    for n in 0..NODES {
+      if async_push {
+        block_on(tx.apush(n)).unwrap();
+      } else {
+        tx.push(n).unwrap();
+      }
+    }
+  });
+
+  let mut expect = 0;
+  loop {
+    let node = if async_pop {
+      block_on(rx.apop())
+    } else {
+      rx.pop()
+    };
+    match node {
+      Some(n) => {
+        assert_eq!(n, expect);
+        expect += 1;
+      }
+      None => break
+    }
+  }
+  assert_eq!(expect, NODES);
+  producer.join().unwrap();
+}
+
+#[test]
+fn blocking() {
+  stream(1, false, false);
+  stream(64, false, false);
+}
+
+#[test]
+fn async_consumer() {
+  stream(1, false, true);
+  stream(64, false, true);
+}
+
+#[test]
+fn async_producer() {
+  stream(1, true, false);
+  stream(64, true, true);
+}
+
+#[test]
+fn pending_apop_is_woken() {
+  let (mut tx, mut rx) = spsc::channel(2);
+  let w = Counter::new();
+  let mut fut = rx.apop();
+  assert!(poll(&mut fut, &w).is_pending());
+  tx.push("hello").unwrap();
+  assert_eq!(w.get(), 1);
+  assert_eq!(poll(&mut fut, &w), Poll::Ready(Some("hello")));
+}
+
+#[test]
+fn full_and_closed() {
+  let (mut tx, mut rx) = spsc::channel(1);
+  tx.try_push(1).unwrap();
+  assert!(matches!(tx.try_push(2), Err(PushError::Full(2))));
+  assert_eq!(rx.pop_timeout(Duration::from_millis(1)), Ok(1));
+  assert_eq!(
+    rx.pop_timeout(Duration::from_millis(10)),
+    Err(PopTimeoutError::Timeout)
+  );
+
+  tx.push(3).unwrap();
+  drop(tx);
+  assert_eq!(rx.try_pop(), Ok(3));
+  assert_eq!(rx.try_pop(), Err(TryPopError::Closed));
+}
+
+/// A timeout too long to be represented waits for as long as it takes.
+#[test]
+fn pop_timeout_too_long_to_represent() {
+  let (mut tx, mut rx) = spsc::channel(1);
+  let producer = thread::spawn(move || {
+    thread::sleep(Duration::from_millis(20));
+    tx.push(1).unwrap();
+  });
+  assert_eq!(rx.pop_timeout(Duration::MAX), Ok(1));
+  producer.join().unwrap();
+  assert_eq!(rx.pop_timeout(Duration::MAX), Err(PopTimeoutError::Closed));
+}
+
+/// Nodes which were never popped are dropped along with the queue.
+#[test]
+fn leftover_nodes_are_dropped() {
+  struct Node(Arc<AtomicUsize>);
+  impl Drop for Node {
+    fn drop(&mut self) {
+      self.0.fetch_add(1, Ordering::SeqCst);
+    }
+  }
+
+  let drops = Arc::new(AtomicUsize::new(0));
+  let (mut tx, mut rx) = spsc::channel(4);
+  for _ in 0..4 {
+    tx.push(Node(Arc::clone(&drops))).unwrap();
+  }
+  rx.pop();
+  rx.pop();
+  drop(rx);
+  assert_eq!(drops.load(Ordering::SeqCst), 2);
+  assert!(matches!(
+    tx.push(Node(Arc::clone(&drops))),
+    Err(PushError::Closed(_))
+  ));
+  assert_eq!(drops.load(Ordering::SeqCst), 3);
+  drop(tx);
+  assert_eq!(drops.load(Ordering::SeqCst), 5);
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,92 @@
+//! Queue statistics keep track of nodes and waiting consumers.
+
+#![cfg(feature = "stats")]
+
+mod common;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use sigq::{channel, Builder, Queue};
+
+use common::{poll, Counter};
+
+#[test]
+fn counts_nodes() {
+  let q = Queue::bounded(4);
+  q.push(1).unwrap();
+  q.push_after(2, Duration::from_secs(60)).unwrap();
+  q.push_iter(vec![3, 4]).unwrap();
+  assert!(q.try_push(5).is_err());
+  assert_eq!(q.pop_batch(2), vec![1, 3]);
+  assert_eq!(q.remove_first(|n| *n == 2), Some(2));
+  assert_eq!(q.try_pop(), Ok(4));
+
+  let stats = q.stats();
+  assert_eq!((stats.pushed, stats.popped), (4, 3));
+  assert_eq!((stats.len, stats.high_water), (0, 4));
+
+  // Handles share the counters.
+  let (tx, rx) = channel();
+  tx.push("hello").unwrap();
+  assert_eq!(rx.pop(), Some("hello"));
+  assert_eq!(tx.stats(), rx.stats());
+  assert_eq!(rx.stats().popped, 1);
+}
+
+fn wait_for_waiters(q: &Queue<u32>, blocked: usize, pending: usize) {
+  for _ in 0..500 {
+    let stats = q.stats();
+    if (stats.blocked, stats.pending) == (blocked, pending) {
+      return;
+    }
+    thread::sleep(Duration::from_millis(1));
+  }
+  panic!(
+    "expected {} blocked and {} pending consumers",
+    blocked, pending
+  );
+}
+
+fn counts_waiters(q: Queue<u32>) {
+  let q = Arc::new(q);
+  let consumers = (0..2)
+    .map(|_| {
+      let q = Arc::clone(&q);
+      thread::spawn(move || q.pop())
+    })
+    .collect::<Vec<_>>();
+  let w = Counter::new();
+  let mut fut = q.apop();
+  assert!(poll(&mut fut, &w).is_pending());
+  wait_for_waiters(&q, 2, 1);
+
+  thread::sleep(Duration::from_millis(20));
+  q.push_iter(vec![1, 2, 3]).unwrap();
+  for consumer in consumers {
+    assert!(consumer.join().unwrap().is_some());
+  }
+  assert!(poll(&mut fut, &w).is_ready());
+  wait_for_waiters(&q, 0, 0);
+
+  // Only consumers which are done waiting count towards the wait time.
+  assert!(q.stats().wait_time >= Duration::from_millis(60));
+  let mut fut = q.apop();
+  assert!(poll(&mut fut, &w).is_pending());
+  wait_for_waiters(&q, 0, 1);
+  drop(fut);
+  wait_for_waiters(&q, 0, 0);
+}
+
+#[test]
+fn waiters() {
+  counts_waiters(Queue::new());
+}
+
+#[test]
+fn fair_waiters() {
+  counts_waiters(Builder::new().fair(true).build());
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,141 @@
+//! Idle workers of a steal group take nodes from their siblings, and every
+//! node is handed out exactly once.
+
+mod common;
+
+use std::sync::{Arc, Barrier};
+use std::task::Poll;
+use std::thread;
+use std::time::Duration;
+
+use sigq::{StealGroup, TryPopError};
+
+use common::{block_on, poll, Counter};
+
+#[test]
+fn own_queue_first_then_steal_from_the_back() {
+  let group = StealGroup::new(3);
+  let (w0, w1) = (group.worker(0), group.worker(1));
+  for n in 0..3 {
+    w0.push(n).unwrap();
+  }
+  w1.push(10).unwrap();
+  group.push_to(2, 20).unwrap();
+
+  assert_eq!(w1.try_pop(), Ok(10));
+  assert_eq!(w1.try_pop(), Ok(20));
+  assert_eq!(w1.try_pop(), Ok(2));
+  assert_eq!(w0.try_pop(), Ok(0));
+  assert_eq!(w0.try_pop(), Ok(1));
+  assert_eq!(w0.try_pop(), Err(TryPopError::Empty));
+  group.close();
+  assert_eq!(w1.try_pop(), Err(TryPopError::Closed));
+}
+
+/// A push onto a busy worker's queue wakes up an idle sibling.
+#[test]
+fn push_wakes_idle_stealer() {
+  let group = StealGroup::new(2);
+  let idle = group.worker(1);
+  let stealer = thread::spawn(move || idle.pop());
+  thread::sleep(Duration::from_millis(50));
+  group.push_to(0, "work").unwrap();
+  assert_eq!(stealer.join().unwrap(), Some("work"));
+
+  let w = Counter::new();
+  let worker = group.worker(1);
+  let mut fut = worker.apop();
+  assert!(poll(&mut fut, &w).is_pending());
+  group.push_to(0, "more").unwrap();
+  assert_eq!(w.get(), 1);
+  assert_eq!(poll(&mut fut, &w), Poll::Ready(Some("more")));
+}
+
+/// A push wakes up a blocked worker rather than a pending async one, and not
+/// both.
+#[test]
+fn push_wakes_one_worker() {
+  let group = StealGroup::new(2);
+  let w = Counter::new();
+  let worker = group.worker(0);
+  let mut fut = worker.apop();
+  assert!(poll(&mut fut, &w).is_pending());
+  let blocked = group.worker(1);
+  let blocked = thread::spawn(move || blocked.pop());
+  thread::sleep(Duration::from_millis(50));
+
+  group.push_to(0, 1).unwrap();
+  assert_eq!(blocked.join().unwrap(), Some(1));
+  assert_eq!(w.get(), 0);
+  group.push_to(1, 2).unwrap();
+  assert_eq!(w.get(), 1);
+  assert_eq!(poll(&mut fut, &w), Poll::Ready(Some(2)));
+}
+
+/// An async worker which is dropped after having been woken up passes the
+/// wakeup on.
+#[test]
+fn dropped_apop_passes_wakeup_on() {
+  let group = StealGroup::new(2);
+  let (w0, w1) = (group.worker(0), group.worker(1));
+  let (c0, c1) = (Counter::new(), Counter::new());
+  let mut fut0 = w0.apop();
+  let mut fut1 = w1.apop();
+  assert!(poll(&mut fut0, &c0).is_pending());
+  assert!(poll(&mut fut1, &c1).is_pending());
+
+  group.push_to(0, 1).unwrap();
+  assert_eq!((c0.get(), c1.get()), (1, 0));
+  drop(fut0);
+  assert_eq!(c1.get(), 1);
+  assert_eq!(poll(&mut fut1, &c1), Poll::Ready(Some(1)));
+}
+
+/// Producers push onto a single worker's queue while a mix of blocking and
+/// async workers compete for the nodes.
+#[test]
+fn workers_share_the_load() {
+  const WORKERS: usize = 4;
+  const NODES: usize = 20_000;
+  let group = StealGroup::new(WORKERS);
+  let start = Arc::new(Barrier::new(WORKERS + 1));
+
+  let workers = (0..WORKERS)
+    .map(|idx| {
+      let worker = group.worker(idx);
+      let start = Arc::clone(&start);
+      thread::spawn(move || {
+        start.wait();
+        let mut got = Vec::new();
+        if idx % 2 == 0 {
+          while let Some(node) = worker.pop() {
+            got.push(node);
+          }
+        } else {
+          block_on(async {
+            while let Some(node) = worker.apop().await {
+              got.push(node);
+            }
+          });
+        }
+        got
+      })
+    })
+    .collect::<Vec<_>>();
+
+  start.wait();
+  for n in 0..NODES {
+    group.push_to(n % 2, n).unwrap();
+  }
+  group.close();
+
+  let mut all = workers
+    .into_iter()
+    .flat_map(|w| w.join().unwrap())
+    .collect::<Vec<_>>();
+  all.sort_unstable();
+  assert_eq!(all, (0..NODES).collect::<Vec<_>>());
+  assert!(group.is_empty());
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,55 @@
+//! Receivers yield nodes as a `Stream` until the queue has been closed and
+//! drained.
+
+#![cfg(feature = "futures-core")]
+
+mod common;
+
+use std::thread;
+
+use futures::executor::block_on;
+use futures::StreamExt;
+
+use sigq::channel;
+
+use common::{poll, Counter};
+
+#[test]
+fn collect_until_senders_dropped() {
+  let (tx, rx) = channel();
+  let tx2 = tx.clone();
+  let producer = thread::spawn(move || {
+    for n in 0..100 {
+      tx.push(n).unwrap();
+    }
+  });
+  drop(tx2);
+  let nodes = block_on(rx.collect::<Vec<_>>());
+  assert_eq!(nodes, (0..100).collect::<Vec<_>>());
+  producer.join().unwrap();
+}
+
+#[test]
+fn dropped_stream_passes_wakeup_on() {
+  let (tx, mut rx1) = channel();
+  let mut rx2 = rx1.clone();
+  let (w1, w2) = (Counter::new(), Counter::new());
+
+  let mut next1 = rx1.next();
+  assert!(poll(&mut next1, &w1).is_pending());
+  drop(next1);
+  let mut next2 = rx2.next();
+  assert!(poll(&mut next2, &w2).is_pending());
+  drop(next2);
+
+  // The first receiver is woken up for the node, but goes away without
+  // taking it.
+  tx.push("hello").unwrap();
+  assert_eq!((w1.get(), w2.get()), (1, 0));
+  drop(rx1);
+  assert_eq!(w2.get(), 1);
+  let mut next2 = rx2.next();
+  assert!(poll(&mut next2, &w2).is_ready());
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
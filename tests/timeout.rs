@@ -0,0 +1,76 @@
+//! Async pop timeouts fire without any particular runtime driving them.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::executor::block_on;
+
+use sigq::{PopTimeoutError, Queue};
+
+#[test]
+fn apop_timeout_fires() {
+  let q: Queue<u32> = Queue::new();
+  let start = Instant::now();
+  let res = block_on(q.apop_timeout(Duration::from_millis(50)));
+  assert_eq!(res, Err(PopTimeoutError::Timeout));
+  assert!(start.elapsed() >= Duration::from_millis(50));
+
+  // The queue is still usable once the timer has fired.
+  q.push(1).unwrap();
+  assert_eq!(block_on(q.apop_timeout(Duration::from_millis(50))), Ok(1));
+}
+
+#[test]
+fn node_arrives_before_deadline() {
+  let q = Arc::new(Queue::new());
+  let producer = {
+    let q = Arc::clone(&q);
+    thread::spawn(move || {
+      thread::sleep(Duration::from_millis(20));
+      q.push("hello").unwrap();
+    })
+  };
+  let start = Instant::now();
+  let deadline = start + Duration::from_secs(10);
+  assert_eq!(block_on(q.apop_deadline(deadline)), Ok("hello"));
+  assert!(start.elapsed() < Duration::from_secs(10));
+  producer.join().unwrap();
+}
+
+#[test]
+fn deadline_in_the_past() {
+  let q = Queue::new();
+  let past = Instant::now();
+  thread::sleep(Duration::from_millis(1));
+  assert_eq!(
+    block_on(q.apop_deadline(past)),
+    Err(PopTimeoutError::Timeout)
+  );
+
+  // Available nodes are still handed out.
+  q.push(1).unwrap();
+  assert_eq!(block_on(q.apop_deadline(past)), Ok(1));
+
+  q.close();
+  assert_eq!(
+    block_on(q.apop_deadline(past)),
+    Err(PopTimeoutError::Closed)
+  );
+}
+
+#[test]
+fn timeout_too_large_to_represent() {
+  let q = Arc::new(Queue::new());
+  let producer = {
+    let q = Arc::clone(&q);
+    thread::spawn(move || {
+      thread::sleep(Duration::from_millis(20));
+      q.push(1).unwrap();
+    })
+  };
+  assert_eq!(block_on(q.apop_timeout(Duration::MAX)), Ok(1));
+  producer.join().unwrap();
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,159 @@
+//! Queue events are reported through `tracing`, tagged with the queue's
+//! name.
+
+#![cfg(feature = "tracing")]
+
+mod common;
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+use sigq::{Builder, PopTimeoutError, Queue};
+
+use common::{poll, Counter};
+
+/// Fields of a span or an event.
+#[derive(Default)]
+struct Fields {
+  queue: Option<String>,
+  op: Option<String>,
+  message: Option<String>
+}
+
+impl Visit for Fields {
+  fn record_str(&mut self, field: &Field, value: &str) {
+    match field.name() {
+      "queue" => self.queue = Some(value.to_string()),
+      "op" => self.op = Some(value.to_string()),
+      _ => {}
+    }
+  }
+
+  fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+    if field.name() == "message" {
+      self.message = Some(format!("{:?}", value));
+    }
+  }
+}
+
+/// Messages of events and operations of spans, along with queue names.
+type Log = Vec<(String, Option<String>)>;
+
+/// Subscriber which records the messages of events and the operations of
+/// spans, along with the queue names they carry.
+#[derive(Clone, Default)]
+struct Recorder {
+  log: Arc<Mutex<Log>>,
+  next_id: Arc<AtomicU64>
+}
+
+impl Recorder {
+  fn take(&self) -> Log {
+    std::mem::take(&mut *self.log.lock().unwrap())
+  }
+}
+
+impl Subscriber for Recorder {
+  fn enabled(&self, _: &Metadata<'_>) -> bool {
+    true
+  }
+
+  fn new_span(&self, span: &Attributes<'_>) -> Id {
+    let mut fields = Fields::default();
+    span.record(&mut fields);
+    let op = format!("span {}", fields.op.unwrap_or_default());
+    self.log.lock().unwrap().push((op, fields.queue));
+    Id::from_u64(self.next_id.fetch_add(1, Ordering::SeqCst) + 1)
+  }
+
+  fn record(&self, _: &Id, _: &Record<'_>) {}
+
+  fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+  fn event(&self, event: &Event<'_>) {
+    let mut fields = Fields::default();
+    event.record(&mut fields);
+    let message = fields.message.unwrap_or_default();
+    self.log.lock().unwrap().push((message, fields.queue));
+  }
+
+  fn enter(&self, _: &Id) {}
+
+  fn exit(&self, _: &Id) {}
+}
+
+fn messages(log: &Log) -> Vec<&str> {
+  log.iter().map(|(msg, _)| msg.as_str()).collect()
+}
+
+#[test]
+fn events_carry_queue_name() {
+  let recorder = Recorder::default();
+  let q: Queue<u32> = Builder::new().name("jobs").build();
+  assert_eq!(q.name(), Some("jobs"));
+
+  tracing::subscriber::with_default(recorder.clone(), || {
+    assert_eq!(
+      q.pop_timeout(Duration::from_millis(1)),
+      Err(PopTimeoutError::Timeout)
+    );
+  });
+  let log = recorder.take();
+  assert!(log
+    .iter()
+    .all(|(_, queue)| queue.as_deref() == Some("jobs")));
+  let msgs = messages(&log);
+  assert_eq!(msgs.first(), Some(&"span pop_deadline"));
+  assert!(msgs.contains(&"blocked"));
+  assert!(msgs.contains(&"unblocked"));
+
+  tracing::subscriber::with_default(recorder.clone(), || {
+    let w = Counter::new();
+    let mut fut = q.apop();
+    assert!(poll(&mut fut, &w).is_pending());
+    q.push(1).unwrap();
+    assert!(poll(&mut fut, &w).is_ready());
+  });
+  let log = recorder.take();
+  assert!(log
+    .iter()
+    .all(|(_, queue)| queue.as_deref() == Some("jobs")));
+  assert_eq!(
+    messages(&log),
+    vec![
+      "span apop",
+      "pending",
+      "span push",
+      "pushed",
+      "waking consumers",
+      "span apop",
+      "done waiting",
+      "popped"
+    ]
+  );
+}
+
+#[test]
+fn unnamed_queue() {
+  let recorder = Recorder::default();
+  let q = Queue::new();
+  assert_eq!(q.name(), None);
+  tracing::subscriber::with_default(recorder.clone(), || {
+    q.push("hello").unwrap();
+    q.pop();
+  });
+  let log = recorder.take();
+  assert_eq!(
+    messages(&log),
+    vec!["span push", "pushed", "span pop", "popped"]
+  );
+  assert!(log.iter().all(|(_, queue)| queue.is_none()));
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :
@@ -0,0 +1,200 @@
+//! Make sure that no mix of blocking and async consumers can leave a node
+//! sitting on a queue while a consumer is asleep.
+
+mod common;
+
+use std::sync::Arc;
+use std::task::Poll;
+use std::thread;
+use std::time::Duration;
+
+use sigq::{Builder, Queue};
+
+use common::{block_on, poll, Counter};
+
+/// A pending async consumer which is never polled again must not keep a
+/// blocked thread from getting the node.
+#[test]
+fn idle_apop_does_not_starve_pop() {
+  let q = Arc::new(Queue::new());
+  let w = Counter::new();
+  let mut fut = q.apop();
+  assert!(poll(&mut fut, &w).is_pending());
+
+  let q2 = Arc::clone(&q);
+  let consumer = thread::spawn(move || q2.pop_timeout(Duration::from_secs(5)));
+  thread::sleep(Duration::from_millis(50));
+  q.push(1).unwrap();
+
+  // Only the blocked thread is woken up for the node.
+  assert_eq!(consumer.join().unwrap(), Ok(1));
+  assert_eq!(w.get(), 0);
+}
+
+/// An async consumer which is dropped after having been woken up for a node
+/// must pass the wakeup on.
+#[test]
+fn dropped_apop_passes_wakeup_on() {
+  let q = Queue::new();
+  let (w1, w2) = (Counter::new(), Counter::new());
+  let mut fut1 = q.apop();
+  let mut fut2 = q.apop();
+  assert!(poll(&mut fut1, &w1).is_pending());
+  assert!(poll(&mut fut2, &w2).is_pending());
+
+  q.push(1).unwrap();
+  assert_eq!((w1.get(), w2.get()), (1, 0));
+  drop(fut1);
+  assert_eq!(w2.get(), 1);
+  assert_eq!(poll(&mut fut2, &w2), Poll::Ready(Some(1)));
+}
+
+/// A consumer which was already waiting when a delayed node was pushed must
+/// be woken up once the node becomes due, even if the consumer which was
+/// told about the node leaves with another one.
+#[test]
+fn waiters_learn_about_delayed_nodes() {
+  let q = Queue::new();
+  let (w1, w2) = (Counter::new(), Counter::new());
+  let mut fut1 = q.apop();
+  let mut fut2 = q.apop();
+  assert!(poll(&mut fut1, &w1).is_pending());
+  assert!(poll(&mut fut2, &w2).is_pending());
+
+  q.push_after("later", Duration::from_millis(20)).unwrap();
+  assert!(poll(&mut fut1, &w1).is_pending());
+  if w2.get() > 0 {
+    assert!(poll(&mut fut2, &w2).is_pending());
+  }
+  q.push("now").unwrap();
+  assert_eq!(poll(&mut fut1, &w1), Poll::Ready(Some("now")));
+
+  let woken = w2.get();
+  thread::sleep(Duration::from_millis(100));
+  assert!(w2.get() > woken);
+  assert_eq!(poll(&mut fut2, &w2), Poll::Ready(Some("later")));
+}
+
+/// Pushing a batch of nodes wakes up exactly as many consumers as there are
+/// new nodes.
+#[test]
+fn batch_wakes_one_consumer_per_node() {
+  let mut q = Queue::new();
+  let counters = (0..5).map(|_| Counter::new()).collect::<Vec<_>>();
+  let mut futs = counters
+    .iter()
+    .map(|w| {
+      let mut fut = q.apop();
+      assert!(poll(&mut fut, w).is_pending());
+      fut
+    })
+    .collect::<Vec<_>>();
+  let woken = || counters.iter().map(|w| w.get()).sum::<usize>();
+
+  q.push_iter(0..3).unwrap();
+  assert_eq!(woken(), 3);
+  q.extend(Some(3));
+  assert_eq!(woken(), 4);
+  for (fut, w) in futs.iter_mut().zip(&counters) {
+    if w.get() > 0 {
+      assert!(poll(fut, w).is_ready());
+    }
+  }
+}
+
+/// Blocked threads are woken up before async consumers, and only once each.
+#[test]
+fn batch_wakes_blocked_threads_first() {
+  let q = Arc::new(Queue::new());
+  let w = Counter::new();
+  let mut fut = q.apop();
+  assert!(poll(&mut fut, &w).is_pending());
+  let consumers = (0..2)
+    .map(|_| {
+      let q = Arc::clone(&q);
+      thread::spawn(move || q.pop())
+    })
+    .collect::<Vec<_>>();
+  thread::sleep(Duration::from_millis(50));
+
+  q.push_iter(0..2).unwrap();
+  for consumer in consumers {
+    assert!(consumer.join().unwrap().is_some());
+  }
+  assert_eq!(w.get(), 0);
+  q.push(2).unwrap();
+  assert_eq!(w.get(), 1);
+  assert_eq!(poll(&mut fut, &w), Poll::Ready(Some(2)));
+}
+
+/// Hammer a queue with producers and a mix of blocking and async consumers,
+/// and make sure every node is delivered exactly once.
+fn mixed_consumers(q: Queue<usize>) {
+  const PRODUCERS: usize = 4;
+  const NODES: usize = 5000;
+  let q = Arc::new(q);
+
+  let mut consumers = Vec::new();
+  for n in 0..6 {
+    let q = Arc::clone(&q);
+    consumers.push(thread::spawn(move || {
+      let mut got = Vec::new();
+      if n % 2 == 0 {
+        while let Some(node) = q.pop() {
+          got.push(node);
+        }
+      } else {
+        block_on(async {
+          while let Some(node) = q.apop().await {
+            got.push(node);
+          }
+        });
+      }
+      got
+    }));
+  }
+
+  let producers = (0..PRODUCERS)
+    .map(|p| {
+      let q = Arc::clone(&q);
+      thread::spawn(move || {
+        for n in 0..NODES {
+          q.push(p * NODES + n).unwrap();
+        }
+      })
+    })
+    .collect::<Vec<_>>();
+  for producer in producers {
+    producer.join().unwrap();
+  }
+  q.close();
+
+  let mut all = consumers
+    .into_iter()
+    .flat_map(|c| c.join().unwrap())
+    .collect::<Vec<_>>();
+  all.sort_unstable();
+  assert_eq!(all, (0..PRODUCERS * NODES).collect::<Vec<_>>());
+}
+
+#[test]
+fn mixed_consumers_unbounded() {
+  mixed_consumers(Queue::new());
+}
+
+#[test]
+fn mixed_consumers_bounded() {
+  mixed_consumers(Queue::bounded(3));
+}
+
+#[test]
+fn mixed_consumers_fair() {
+  mixed_consumers(Builder::new().fair(true).build());
+}
+
+#[test]
+fn mixed_consumers_fair_bounded() {
+  mixed_consumers(Builder::new().bounded(3).fair(true).build());
+}
+
+// vim: set ft=rust et sw=2 ts=2 sts=2 cinoptions=2 tw=79 :